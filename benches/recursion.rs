@@ -0,0 +1,50 @@
+//! Times recursive programs, run with `cargo bench`.
+//!
+//! Each program is parsed once, then evaluated in a fresh interpreter several times, and the median time is reported for both engines. The optimizer is disabled, as it would remove the work some of them measure.
+
+use kotek::{Interpreter, eval::Engine};
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 15;
+
+/// Definitions that are never called, but make the session larger, as real programs are.
+fn padding() -> String {
+    (0..50).map(|i| format!("let unused{} ( {} dup * [1 2 3 4 5 6 7 8] pop )\n", i, i)).collect()
+}
+
+/// The median time taken to evaluate `src` with `engine`.
+fn median(src: &str, engine: Engine) -> Duration {
+    let mut times = (0..RUNS).map(|_| {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_engine(engine);
+        interpreter.set_optimize(false);
+        let expressions = interpreter.parse(src).expect("the benchmark should parse");
+        let start = Instant::now();
+        interpreter.eval(expressions).expect("the benchmark should evaluate");
+        start.elapsed()
+    }).collect::<Vec<Duration>>();
+    times.sort();
+    times[RUNS / 2]
+}
+
+fn bench(name: &str, src: &str) {
+    let (tree, bytecode) = (median(src, Engine::TreeWalker), median(src, Engine::Bytecode));
+    println!("{:<16} {:>10.3} ms (tree) {:>10.3} ms (bytecode)", name, tree.as_secs_f64() * 1000., bytecode.as_secs_f64() * 1000.);
+}
+
+fn main() {
+    let padding = padding();
+    bench("fib", &format!("{}
+let fib ( dup 2 lt [] [dup 1 - fib swap 2 - fib +] if )
+20 fib", padding));
+    bench("fact", &format!("{}
+let fact ( dup 1 eq [pop 1] [dup 1 - fact *] if )
+let loop ( dup 0 eq [pop] [12 fact pop 1 - loop] if )
+2000 loop", padding));
+    bench("countdown", &format!("{}
+let loop ( dup 0 eq [pop] [1 - loop] if )
+100000 loop", padding));
+    bench("dup quote", &format!("{}
+let loop ( dup 0 eq [pop] [swap dup pop swap 1 - loop] if )
+[{}] 100000 loop", padding, "1 2 3 [4 5 [6 7]] \"eight\" ".repeat(50)));
+}
@@ -1,20 +1,131 @@
-use crate::{eval::{Evaluator, Value}, Result, error, Error};
+use std::rc::Rc;
+use crate::{eval::{Evaluator, Value, HostFn}, parser::Expr, Result, Error, ErrorKind, error};
+
+type NativeFn = fn(&mut Evaluator, usize, usize) -> Result<()>;
+
+/// The native builtins: name, stack signature, accepted types, documentation and implementation.
+///
+/// `ExprT::Builtin` indexes this table, host builtins being appended after it.
+const NATIVES: [(&str, &str, &str, &str, NativeFn); 16] = [
+    ("+", "a b -- a+b", "Integer/Real", "Adds two numbers.", Evaluator::add),
+    ("-", "a b -- a-b", "Integer/Real", "Substract one number to another.", Evaluator::sub),
+    ("*", "a b -- a*b", "Integer/Real", "Multiply two numbers.", Evaluator::mul),
+    ("/", "a b -- a/b", "Integer/Real", "Divide one nunber by another.", Evaluator::div),
+    ("%", "a b -- a%b", "Integer/Real", "Get the remainder of the division of one number by another.", Evaluator::r#mod),
+    ("dup", "x -- x x", "Any", "Duplicate the top of the stack.", Evaluator::dup),
+    ("app", "q -- ...", "Quote", "Unquote the top of the stack.", Evaluator::app),
+    ("cat", "a b -- ab", "String", "Concatenate two strings.", Evaluator::cat),
+    ("pop", "x --", "Any", "Remove the top of the stack.", Evaluator::pop_stack),
+    ("swap", "x y -- y x", "Any", "Swap the top of the stack with the value below it.", Evaluator::swap),
+    ("print_stack", "--", "N/A", "Print the stack.", Evaluator::print_stack),
+    ("eq", "a b -- bool", "!Quote", "Test equality between two values.", Evaluator::eq),
+    ("not", "bool -- bool", "Symbol", "Push `#t` if top of the stack is `#f` and vice-versa.", Evaluator::not),
+    ("gt", "a b -- bool", "Integer/Real/String", "Test if a value if greater than another value.", Evaluator::gt),
+    ("lt", "a b -- bool", "Integer/Real/String", "Test if a value if less than another value.", Evaluator::lt),
+    ("if", "cond then else -- ...", "Symbol-Quote-Quote", "`$cond $then $else if` if $cond, apply $then, and if not, apply $else.", Evaluator::r#if),
+];
+
+enum Func {
+    Native(NativeFn),
+    Host(Option<HostFn>),
+}
+
+/// An entry of the builtin registry.
+pub struct Builtin {
+    pub name: String,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub types: String,
+    pub doc: String,
+    func: Func,
+}
+impl Builtin {
+    fn new(name: impl ToString, signature: &str, types: impl ToString, doc: impl ToString, func: Func) -> Self {
+        let mut sides = signature.splitn(2, "--").map(|side| side.split_whitespace().map(String::from).collect());
+        Self {
+            name: name.to_string(),
+            inputs: sides.next().unwrap_or_default(),
+            outputs: sides.next().unwrap_or_default(),
+            types: types.to_string(),
+            doc: doc.to_string(),
+            func,
+        }
+    }
+    pub fn native_names() -> impl Iterator<Item = &'static str> {
+        NATIVES.iter().map(|native| native.0)
+    }
+    /// The index of the native builtin named `name`.
+    pub(crate) fn native_index(name: &str) -> Option<u16> {
+        Self::native_names().position(|native| native == name).map(|idx| idx as u16)
+    }
+    pub fn natives() -> Vec<Builtin> {
+        NATIVES.iter().map(|(name, signature, types, doc, func)| Self::new(name, signature, types, doc, Func::Native(*func))).collect()
+    }
+    pub fn host(name: impl ToString, arity: usize, doc: impl ToString, func: HostFn) -> Self {
+        let inputs = (1..=arity).map(|i| format!("x{}", i)).collect::<Vec<_>>().join(" ");
+        Self::new(name, &format!("{} -- ...", inputs), "Any", doc, Func::Host(Some(func)))
+    }
+    pub fn is_host(&self) -> bool {
+        matches!(self.func, Func::Host(_))
+    }
+    pub fn arity(&self) -> usize {
+        self.inputs.len()
+    }
+    /// The stack signature, e.g. `( a b -- a+b )`.
+    pub fn signature(&self) -> String {
+        let mut words = self.inputs.clone();
+        words.push("--".to_string());
+        words.extend(self.outputs.iter().cloned());
+        format!("( {} )", words.join(" "))
+    }
+}
+
+/// Renders the builtin table of the README.
+pub fn markdown_table() -> String {
+    let mut table = "| Name | Signature | Args type | Description |\n|------|-----------|-----------|-------------|\n".to_string();
+    for builtin in Builtin::natives() {
+        table.push_str(&format!("| `{}` | `{}` | {} | {} |\n", builtin.name, builtin.signature(), builtin.types, builtin.doc));
+    }
+    table
+}
 
 impl Evaluator {
+    pub fn call_builtin(&mut self, idx: usize, line: usize, column: usize) -> Result<()> {
+        let builtin = &mut self.builtins[idx];
+        if self.stack.len() < builtin.arity() {
+            return Err(underflow(&builtin.name, builtin.arity(), self.stack.len(), line, column));
+        }
+        match &mut builtin.func {
+            Func::Native(func) => (*func)(self, line, column),
+            Func::Host(func) => {
+                let mut func = match func.take() {
+                    Some(func) => func,
+                    None => return error!(line, column, ErrorKind::RecursiveHostCall(builtin.name.clone())),
+                };
+                let res = func(self);
+                self.builtins[idx].func = Func::Host(Some(func));
+                res.map_err(|e| Error { line, column, ..e })
+            }
+        }
+    }
+
     pub fn add(&mut self, line: usize, column: usize) -> Result<()> {
         let rhs = self.pop(line, column)?;
         let lhs = self.pop(line, column)?;
 
         match lhs {
             Value::Integer(lhs) => match rhs {
-                Value::Integer(rhs) => self.push(Value::Integer(lhs + rhs)),
-                _ => error!(line, column, "Expected an Integer, found a {}.", rhs.get_type()),
+                Value::Integer(rhs) => match lhs.checked_add(rhs) {
+                    Some(z) => self.push(Value::Integer(z)),
+                    None => error!(line, column, ErrorKind::IntegerOverflow),
+                }
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Integer"], found: rhs.get_type() }),
             }
             Value::Real(lhs) => match rhs {
                 Value::Real(rhs) => self.push(Value::Real(lhs + rhs)),
-                _ => error!(line, column, "Expected a Real, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real"], found: rhs.get_type() }),
             }
-            _ => error!(line, column, "Expected a Real or an Integer, found a {}.", lhs.get_type()),
+            _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real", "Integer"], found: lhs.get_type() }),
         }
     }
     pub fn sub(&mut self, line: usize, column: usize) -> Result<()> {
@@ -23,14 +134,17 @@ impl Evaluator {
 
         match lhs {
             Value::Integer(lhs) => match rhs {
-                Value::Integer(rhs) => self.push(Value::Integer(lhs - rhs)),
-                _ => error!(line, column, "Expected an Integer, found a {}.", rhs.get_type()),
+                Value::Integer(rhs) => match lhs.checked_sub(rhs) {
+                    Some(z) => self.push(Value::Integer(z)),
+                    None => error!(line, column, ErrorKind::IntegerOverflow),
+                }
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Integer"], found: rhs.get_type() }),
             }
             Value::Real(lhs) => match rhs {
                 Value::Real(rhs) => self.push(Value::Real(lhs - rhs)),
-                _ => error!(line, column, "Expected a Real, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real"], found: rhs.get_type() }),
             }
-            _ => error!(line, column, "Expected a Real or an Integer, found a {}.", lhs.get_type()),
+            _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real", "Integer"], found: lhs.get_type() }),
         }
     }
     pub fn mul(&mut self, line: usize, column: usize) -> Result<()> {
@@ -39,14 +153,17 @@ impl Evaluator {
 
         match lhs {
             Value::Integer(lhs) => match rhs {
-                Value::Integer(rhs) => self.push(Value::Integer(lhs * rhs)),
-                _ => error!(line, column, "Expected an Integer, found a {}.", rhs.get_type()),
+                Value::Integer(rhs) => match lhs.checked_mul(rhs) {
+                    Some(z) => self.push(Value::Integer(z)),
+                    None => error!(line, column, ErrorKind::IntegerOverflow),
+                }
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Integer"], found: rhs.get_type() }),
             }
             Value::Real(lhs) => match rhs {
                 Value::Real(rhs) => self.push(Value::Real(lhs * rhs)),
-                _ => error!(line, column, "Expected a Real, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real"], found: rhs.get_type() }),
             }
-            _ => error!(line, column, "Expected a Real or an Integer, found a {}.", lhs.get_type()),
+            _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real", "Integer"], found: lhs.get_type() }),
         }
     }
     pub fn div(&mut self, line: usize, column: usize) -> Result<()> {
@@ -55,14 +172,18 @@ impl Evaluator {
 
         match lhs {
             Value::Integer(lhs) => match rhs {
-                Value::Integer(rhs) => self.push(Value::Integer(lhs / rhs)),
-                _ => error!(line, column, "Expected an Integer, found a {}.", rhs.get_type()),
+                Value::Integer(0) => error!(line, column, ErrorKind::DivisionByZero),
+                Value::Integer(rhs) => match lhs.checked_div(rhs) {
+                    Some(z) => self.push(Value::Integer(z)),
+                    None => error!(line, column, ErrorKind::IntegerOverflow),
+                }
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Integer"], found: rhs.get_type() }),
             }
             Value::Real(lhs) => match rhs {
                 Value::Real(rhs) => self.push(Value::Real(lhs / rhs)),
-                _ => error!(line, column, "Expected a Real, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real"], found: rhs.get_type() }),
             }
-            _ => error!(line, column, "Expected a Real or an Integer, found a {}.", lhs.get_type()),
+            _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real", "Integer"], found: lhs.get_type() }),
         }
     }
     pub fn r#mod(&mut self, line: usize, column: usize) -> Result<()> {
@@ -71,14 +192,18 @@ impl Evaluator {
 
         match lhs {
             Value::Integer(lhs) => match rhs {
-                Value::Integer(rhs) => self.push(Value::Integer(lhs % rhs)),
-                _ => error!(line, column, "Expected an Integer, found a {}.", rhs.get_type()),
+                Value::Integer(0) => error!(line, column, ErrorKind::DivisionByZero),
+                Value::Integer(rhs) => match lhs.checked_rem(rhs) {
+                    Some(z) => self.push(Value::Integer(z)),
+                    None => error!(line, column, ErrorKind::IntegerOverflow),
+                }
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Integer"], found: rhs.get_type() }),
             }
             Value::Real(lhs) => match rhs {
                 Value::Real(rhs) => self.push(Value::Real(lhs % rhs)),
-                _ => error!(line, column, "Expected a Real, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real"], found: rhs.get_type() }),
             }
-            _ => error!(line, column, "Expected a Real or an Integer, found a {}.", lhs.get_type()),
+            _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real", "Integer"], found: lhs.get_type() }),
         }
     }
 
@@ -89,19 +214,16 @@ impl Evaluator {
         match lhs {
             Value::String(lhs) => match rhs {
                 Value::String(rhs) => self.push(Value::String(format!("{}{}", lhs, rhs))),
-                _ => error!(line, column, "Expected a String, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["String"], found: rhs.get_type() }),
             }
-            _ => error!(line, column, "Expected a String, found a {}.", lhs.get_type()),
+            _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["String"], found: lhs.get_type() }),
         }
     }
 
     pub fn app(&mut self, line: usize, column: usize) -> Result<()> {
         let val = self.pop(line, column)?;
-
-        match val {
-            Value::Quote(exprs) => exprs.into_iter().map(|expr| self.eval_expr(expr)).collect::<Result<()>>(),
-            _ => error!(line, column, "Expected a Quote, found a {}.", val.get_type()),
-        }
+        let exprs = quote(val, line, column)?;
+        self.call(exprs, line, column)
     }
     pub fn dup(&mut self, line: usize, column: usize) -> Result<()> {
         let to_dup = self.pop(line, column)?;
@@ -136,9 +258,9 @@ impl Evaluator {
             } else if boolean.as_str() == "f" {
                 self.push(Value::Symbol("t".to_string()))
             } else {
-                error!(line, column, "Expected #t or #f, found #{}.", boolean)
+                error!(line, column, ErrorKind::ExpectedBoolean(boolean))
             }
-            _ => error!(line, column, "Expected a Symbol, found a {}.", popped.get_type()) 
+            _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Symbol"], found: popped.get_type() }) 
         }
     }
     pub fn gt(&mut self, line: usize, column: usize) -> Result<()> {
@@ -148,17 +270,17 @@ impl Evaluator {
         match lhs {
             Value::Integer(lhs) => match rhs {
                 Value::Integer(rhs) => self.push(to_sym(lhs > rhs)),
-                _ => error!(line, column, "Expected an Integer, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Integer"], found: rhs.get_type() }),
             }
             Value::Real(lhs) => match rhs {
                 Value::Real(rhs) => self.push(to_sym(lhs > rhs)),
-                _ => error!(line, column, "Expected a Real, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real"], found: rhs.get_type() }),
             }
             Value::String(lhs) => match rhs {
                 Value::String(rhs) => self.push(to_sym(lhs > rhs)),
-                _ => error!(line, column, "Expected a String, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["String"], found: rhs.get_type() }),
             }
-            _ => error!(line, column, "Expected a Real or an Integer, found a {}.", lhs.get_type()),
+            _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real", "Integer"], found: lhs.get_type() }),
         }
     }
 
@@ -169,40 +291,55 @@ impl Evaluator {
         match lhs {
             Value::Integer(lhs) => match rhs {
                 Value::Integer(rhs) => self.push(to_sym(lhs < rhs)),
-                _ => error!(line, column, "Expected an Integer, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Integer"], found: rhs.get_type() }),
             }
             Value::Real(lhs) => match rhs {
                 Value::Real(rhs) => self.push(to_sym(lhs < rhs)),
-                _ => error!(line, column, "Expected a Real, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real"], found: rhs.get_type() }),
             }
             Value::String(lhs) => match rhs {
                 Value::String(rhs) => self.push(to_sym(lhs < rhs)),
-                _ => error!(line, column, "Expected a String, found a {}.", rhs.get_type()),
+                _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["String"], found: rhs.get_type() }),
             }
-            _ => error!(line, column, "Expected a Real or an Integer, found a {}.", lhs.get_type()),
+            _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Real", "Integer"], found: lhs.get_type() }),
         }
     }
     pub fn r#if(&mut self, line: usize, column: usize) -> Result<()> {
+        let branch = self.branch(line, column)?;
+        self.push(branch)?;
+        self.app(line, column)
+    }
+    /// Pops the condition and the branches of an `if`, returning the chosen branch.
+    pub(crate) fn branch(&mut self, line: usize, column: usize) -> Result<Value> {
         let r#else = self.pop(line, column)?;
         let then = self.pop(line, column)?;
-        let cond = self.pop(line, column)?;
-        if let Value::Symbol(sym) = cond {
-            let sym = sym.as_str();
-            if sym == "t" {
-                self.push(then)?;
-                self.app(line, column)
-            } else if sym == "f" {
-                self.push(r#else)?;
-                self.app(line, column)
-            } else {
-                error!(line, column, "Expected #t or #f, found #{}.", sym)
-            }
-        } else {
-            error!(line, column, "Expected a Symbol, found a {}.", cond.get_type()) 
+        Ok(if self.condition(line, column)? { then } else { r#else })
+    }
+    /// Pops a boolean Symbol.
+    pub(crate) fn condition(&mut self, line: usize, column: usize) -> Result<bool> {
+        match self.pop(line, column)? {
+            Value::Symbol(sym) if sym == "t" => Ok(true),
+            Value::Symbol(sym) if sym == "f" => Ok(false),
+            Value::Symbol(sym) => error!(line, column, ErrorKind::ExpectedBoolean(sym)),
+            cond => error!(line, column, ErrorKind::TypeMismatch { expected: &["Symbol"], found: cond.get_type() }),
         }
     }
 }
 
+/// The error of a builtin taking `arity` values called on a stack holding `held`.
+pub(crate) fn underflow(name: &str, arity: usize, held: usize, line: usize, column: usize) -> Error {
+    let note = format!("`{}` takes {} value{}, but the stack holds {}.", name, arity, if arity == 1 { "" } else { "s" }, held);
+    Error::new(ErrorKind::StackUnderflow, line, column).with_note(note)
+}
+
+/// The content of `val`, if it is a Quote.
+pub(crate) fn quote(val: Value, line: usize, column: usize) -> Result<Rc<[Expr]>> {
+    match val {
+        Value::Quote(exprs) => Ok(exprs),
+        _ => error!(line, column, ErrorKind::TypeMismatch { expected: &["Quote"], found: val.get_type() }),
+    }
+}
+
 fn to_sym(b: bool) -> Value {
     if b { 
         Value::Symbol("t".to_string())
@@ -0,0 +1,499 @@
+use std::{collections::HashMap, convert::TryInto, hash::{BuildHasherDefault, Hasher}, rc::Rc};
+use crate::{builtins::Builtin, eval::Value, parser::{Expr, ExprT, MAX_NESTING}, Result, ErrorKind, error};
+
+/// An instruction of the VM, see `Evaluator::run_block`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    /// Pushes a constant.
+    Push(u32),
+    Builtin(u16),
+    /// Calls a definition, by symbol index.
+    Call(u16),
+    /// Gives a definition the body held by a Quote constant.
+    Store(u16, u32),
+    /// Pops a Quote and calls it.
+    App,
+    /// Pops a condition and two Quotes, and calls one of them.
+    If,
+    /// Pops a condition, and jumps to an instruction of the block if it is `#f`.
+    JumpUnless(u32),
+    Jump(u32),
+    Return,
+}
+
+/// The code of a definition, of a Quote or of a whole source.
+#[derive(Clone, Debug, Default)]
+pub struct Block {
+    pub code: Vec<Op>,
+    /// The line and column of each instruction, for error reporting.
+    pub positions: Vec<(usize, usize)>,
+}
+impl Block {
+    fn emit(&mut self, op: Op, expr: &Expr) -> usize {
+        self.code.push(op);
+        self.positions.push((expr.line, expr.column));
+        self.code.len() - 1
+    }
+}
+
+/// Hashes the addresses of the compiled bodies, which need no protection against collisions.
+#[derive(Default)]
+struct AddressHasher(u64);
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        bytes.iter().for_each(|byte| self.0 = (self.0 << 8 | *byte as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    }
+    fn write_usize(&mut self, address: usize) {
+        self.0 = (address as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+/// Compiled blocks and the constants they use.
+///
+/// Every compiled body is kept as a Quote constant, so that the Quote values sharing it find its block.
+#[derive(Debug, Default)]
+pub struct Program {
+    pub constants: Vec<Value>,
+    pub blocks: Vec<Block>,
+    /// The Quote constant of each compiled body, and its block.
+    pub bodies: Vec<(u32, u32)>,
+    /// The constant and the block of each compiled body, by address.
+    compiled: HashMap<*const Expr, (u32, u32), BuildHasherDefault<AddressHasher>>,
+}
+/// The sizes of a program, see `Program::mark`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Mark {
+    constants: usize,
+    blocks: usize,
+    bodies: usize,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn constant(&mut self, val: Value) -> u32 {
+        self.constants.push(val);
+        (self.constants.len() - 1) as u32
+    }
+    /// The number of constants, blocks and compiled bodies, to forget the ones added later with `truncate`.
+    pub(crate) fn mark(&self) -> Mark {
+        Mark { constants: self.constants.len(), blocks: self.blocks.len(), bodies: self.bodies.len() }
+    }
+    /// Forgets the constants and blocks added since `mark`, which no running block may use.
+    ///
+    /// A body compiled again later gets a new block.
+    pub(crate) fn truncate(&mut self, mark: Mark) {
+        for (constant, _) in &self.bodies[mark.bodies..] {
+            if let Some(Value::Quote(body)) = self.constants.get(*constant as usize) {
+                self.compiled.remove(&(Rc::as_ptr(body) as *const Expr));
+            }
+        }
+        self.constants.truncate(mark.constants);
+        self.blocks.truncate(mark.blocks);
+        self.bodies.truncate(mark.bodies);
+    }
+    /// The constant holding `body` and its block, compiling it the first time.
+    pub fn block_of(&mut self, body: &Rc<[Expr]>) -> (u32, u32) {
+        if let Some(compiled) = self.compiled.get(&(Rc::as_ptr(body) as *const Expr)) {
+            return *compiled;
+        }
+        let constant = self.constant(Value::Quote(body.clone()));
+        // Reserve the block first, so that a body quoting itself cannot be compiled twice.
+        let idx = self.blocks.len() as u32;
+        self.blocks.push(Block::default());
+        self.compiled.insert(Rc::as_ptr(body) as *const Expr, (constant, idx));
+        self.bodies.push((constant, idx));
+
+        let mut block = Block::default();
+        self.emit_body(&mut block, body, true);
+        if let Some(last) = body.last() {
+            block.emit(Op::Return, last);
+        } else {
+            block.code.push(Op::Return);
+            block.positions.push((0, 0));
+        }
+        self.blocks[idx as usize] = block;
+        (constant, idx)
+    }
+    /// Compiles `exprs` at the end of `block`, `tail` telling whether nothing follows them.
+    ///
+    /// `if` is compiled to jumps when both its branches are Quote literals, their code being inlined.
+    fn emit_body(&mut self, block: &mut Block, exprs: &[Expr], tail: bool) {
+        let (app, r#if) = (Builtin::native_index("app"), Builtin::native_index("if"));
+        let mut idx = 0;
+        while idx < exprs.len() {
+            let expr = &exprs[idx];
+            if let (ExprT::Quote(then), Some(ExprT::Quote(r#else)), Some(end)) = (&expr.r#type, exprs.get(idx + 1).map(|e| &e.r#type), exprs.get(idx + 2)) {
+                if matches!(end.r#type, ExprT::Builtin(b) if Some(b) == r#if) {
+                    let last = tail && idx + 3 == exprs.len();
+                    let jump_unless = block.emit(Op::JumpUnless(0), end);
+                    self.emit_body(block, then, last);
+                    let jump = block.emit(if last { Op::Return } else { Op::Jump(0) }, end);
+                    block.code[jump_unless] = Op::JumpUnless(block.code.len() as u32);
+                    self.emit_body(block, r#else, last);
+                    if !last {
+                        block.code[jump] = Op::Jump(block.code.len() as u32);
+                    }
+                    idx += 3;
+                    continue;
+                }
+            }
+            let op = match &expr.r#type {
+                ExprT::Integer(z) => Op::Push(self.constant(Value::Integer(*z))),
+                ExprT::Real(r) => Op::Push(self.constant(Value::Real(*r))),
+                ExprT::String(s) => Op::Push(self.constant(Value::String(s.clone()))),
+                ExprT::Symbol(sym) => Op::Push(self.constant(Value::Symbol(sym.clone()))),
+                ExprT::Quote(body) => Op::Push(self.block_of(body).0),
+                ExprT::Store(var, body) => Op::Store(*var, self.block_of(body).0),
+                ExprT::Var(var) => Op::Call(*var),
+                ExprT::Builtin(b) if Some(*b) == app => Op::App,
+                ExprT::Builtin(b) if Some(*b) == r#if => Op::If,
+                ExprT::Builtin(b) => Op::Builtin(*b),
+            };
+            block.emit(op, expr);
+            idx += 1;
+        }
+    }
+    /// Rebuilds the index of the compiled bodies, after deserialization.
+    fn index(&mut self) -> Result<()> {
+        for (constant, block) in &self.bodies {
+            match self.constants.get(*constant as usize) {
+                Some(Value::Quote(body)) if (*block as usize) < self.blocks.len() => {
+                    self.compiled.insert(Rc::as_ptr(body) as *const Expr, (*constant, *block));
+                }
+                _ => return error!(0, 0, ErrorKind::InvalidBytecode("a compiled body is not a Quote constant".to_string())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the words of `exprs` only refer to existing definitions and builtins.
+fn valid_words(exprs: &[Expr], symbols: usize, builtins: usize) -> bool {
+    exprs.iter().all(|expr| match &expr.r#type {
+        ExprT::Quote(body) => valid_words(body, symbols, builtins),
+        ExprT::Store(var, body) => (*var as usize) < symbols && valid_words(body, symbols, builtins),
+        ExprT::Var(var) => (*var as usize) < symbols,
+        ExprT::Builtin(b) => (*b as usize) < builtins,
+        _ => true,
+    })
+}
+
+/// The first bytes of a `.ktc` file.
+pub const MAGIC: &[u8] = b"KTC\0";
+const VERSION: u32 = 1;
+
+/// A compiled source, as stored in a `.ktc` file.
+///
+/// The source is kept to show the lines errors point to, and the builtin names to check that the file is run with the builtins it was compiled against.
+pub struct Compiled {
+    pub name: String,
+    pub source: String,
+    pub first_line: usize,
+    pub symbols: Vec<String>,
+    pub builtins: Vec<String>,
+    pub program: Program,
+    /// The block of the whole source.
+    pub entry: u32,
+}
+
+struct Writer(Vec<u8>);
+impl Writer {
+    fn u8(&mut self, x: u8) {
+        self.0.push(x);
+    }
+    fn u16(&mut self, x: u16) {
+        self.0.extend(x.to_le_bytes());
+    }
+    fn u32(&mut self, x: u32) {
+        self.0.extend(x.to_le_bytes());
+    }
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.0.extend(s.as_bytes());
+    }
+    fn strs(&mut self, strs: &[String]) {
+        self.len(strs.len());
+        strs.iter().for_each(|s| self.str(s));
+    }
+    fn exprs(&mut self, exprs: &[Expr]) {
+        self.len(exprs.len());
+        for expr in exprs {
+            self.len(expr.line);
+            self.len(expr.column);
+            match &expr.r#type {
+                ExprT::Symbol(sym) => {
+                    self.u8(0);
+                    self.str(sym);
+                }
+                ExprT::String(s) => {
+                    self.u8(1);
+                    self.str(s);
+                }
+                ExprT::Integer(z) => {
+                    self.u8(2);
+                    self.0.extend(z.to_le_bytes());
+                }
+                ExprT::Real(r) => {
+                    self.u8(3);
+                    self.u32(r.to_bits());
+                }
+                ExprT::Quote(body) => {
+                    self.u8(4);
+                    self.exprs(body);
+                }
+                ExprT::Store(var, body) => {
+                    self.u8(5);
+                    self.u16(*var);
+                    self.exprs(body);
+                }
+                ExprT::Var(var) => {
+                    self.u8(6);
+                    self.u16(*var);
+                }
+                ExprT::Builtin(b) => {
+                    self.u8(7);
+                    self.u16(*b);
+                }
+            }
+        }
+    }
+    fn value(&mut self, val: &Value) {
+        match val {
+            Value::Integer(z) => {
+                self.u8(0);
+                self.0.extend(z.to_le_bytes());
+            }
+            Value::Real(r) => {
+                self.u8(1);
+                self.u32(r.to_bits());
+            }
+            Value::String(s) => {
+                self.u8(2);
+                self.str(s);
+            }
+            Value::Symbol(sym) => {
+                self.u8(3);
+                self.str(sym);
+            }
+            Value::Quote(body) => {
+                self.u8(4);
+                self.exprs(body);
+            }
+        }
+    }
+    fn op(&mut self, op: Op) {
+        match op {
+            Op::Push(c) => {
+                self.u8(0);
+                self.u32(c);
+            }
+            Op::Builtin(b) => {
+                self.u8(1);
+                self.u16(b);
+            }
+            Op::Call(var) => {
+                self.u8(2);
+                self.u16(var);
+            }
+            Op::Store(var, c) => {
+                self.u8(3);
+                self.u16(var);
+                self.u32(c);
+            }
+            Op::App => self.u8(4),
+            Op::If => self.u8(5),
+            Op::JumpUnless(target) => {
+                self.u8(6);
+                self.u32(target);
+            }
+            Op::Jump(target) => {
+                self.u8(7);
+                self.u32(target);
+            }
+            Op::Return => self.u8(8),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    /// The number of Quotes and definition bodies being read.
+    depth: usize,
+}
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return error!(0, 0, ErrorKind::InvalidBytecode("the file is truncated".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap_or_default()))
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap_or_default()))
+    }
+    fn len(&mut self) -> Result<usize> {
+        self.u32().map(|len| len as usize)
+    }
+    fn str(&mut self) -> Result<String> {
+        let len = self.len()?;
+        match String::from_utf8(self.take(len)?.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => error!(0, 0, ErrorKind::InvalidBytecode("a string is not valid UTF-8".to_string())),
+        }
+    }
+    fn strs(&mut self) -> Result<Vec<String>> {
+        (0..self.len()?).map(|_| self.str()).collect()
+    }
+    fn invalid<T>(what: &str, tag: u8) -> Result<T> {
+        error!(0, 0, ErrorKind::InvalidBytecode(format!("unknown {} tag {}", what, tag)))
+    }
+    /// Reads a body, nested in at most `MAX_NESTING` others below the top-level one, as the parser allows.
+    fn exprs(&mut self) -> Result<Rc<[Expr]>> {
+        if self.depth > MAX_NESTING {
+            return error!(0, 0, ErrorKind::InvalidBytecode("too deeply nested quotes".to_string()));
+        }
+        let len = self.len()?;
+        self.depth += 1;
+        let exprs = (0..len).map(|_| {
+            let (line, column) = (self.len()?, self.len()?);
+            let r#type = match self.u8()? {
+                0 => ExprT::Symbol(self.str()?),
+                1 => ExprT::String(self.str()?),
+                2 => ExprT::Integer(self.u32()? as i32),
+                3 => ExprT::Real(f32::from_bits(self.u32()?)),
+                4 => ExprT::Quote(self.exprs()?),
+                5 => ExprT::Store(self.u16()?, self.exprs()?),
+                6 => ExprT::Var(self.u16()?),
+                7 => ExprT::Builtin(self.u16()?),
+                tag => return Self::invalid("expression", tag),
+            };
+            Ok(Expr::new(r#type, line, column))
+        }).collect();
+        self.depth -= 1;
+        exprs
+    }
+    fn value(&mut self) -> Result<Value> {
+        Ok(match self.u8()? {
+            0 => Value::Integer(self.u32()? as i32),
+            1 => Value::Real(f32::from_bits(self.u32()?)),
+            2 => Value::String(self.str()?),
+            3 => Value::Symbol(self.str()?),
+            4 => Value::Quote(self.exprs()?),
+            tag => return Self::invalid("constant", tag),
+        })
+    }
+    fn op(&mut self) -> Result<Op> {
+        Ok(match self.u8()? {
+            0 => Op::Push(self.u32()?),
+            1 => Op::Builtin(self.u16()?),
+            2 => Op::Call(self.u16()?),
+            3 => Op::Store(self.u16()?, self.u32()?),
+            4 => Op::App,
+            5 => Op::If,
+            6 => Op::JumpUnless(self.u32()?),
+            7 => Op::Jump(self.u32()?),
+            8 => Op::Return,
+            tag => return Self::invalid("instruction", tag),
+        })
+    }
+}
+
+impl Compiled {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer(MAGIC.to_vec());
+        w.u32(VERSION);
+        w.str(&self.name);
+        w.str(&self.source);
+        w.len(self.first_line);
+        w.strs(&self.symbols);
+        w.strs(&self.builtins);
+        w.len(self.program.constants.len());
+        self.program.constants.iter().for_each(|val| w.value(val));
+        w.len(self.program.bodies.len());
+        for (constant, block) in &self.program.bodies {
+            w.u32(*constant);
+            w.u32(*block);
+        }
+        w.len(self.program.blocks.len());
+        for block in &self.program.blocks {
+            w.len(block.code.len());
+            for (op, (line, column)) in block.code.iter().zip(&block.positions) {
+                w.op(*op);
+                w.len(*line);
+                w.len(*column);
+            }
+        }
+        w.u32(self.entry);
+        w.0
+    }
+    /// Reads a `.ktc` file, checking that its instructions only refer to existing constants, blocks and instructions.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut r = Reader { bytes, depth: 0 };
+        if r.take(MAGIC.len()).ok() != Some(MAGIC) {
+            return error!(0, 0, ErrorKind::InvalidBytecode("not a compiled Kotek file".to_string()));
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return error!(0, 0, ErrorKind::InvalidBytecode(format!("unsupported version {}", version)));
+        }
+        let (name, source, first_line) = (r.str()?, r.str()?, r.len()?);
+        let (symbols, builtins) = (r.strs()?, r.strs()?);
+        let mut program = Program::new();
+        program.constants = (0..r.len()?).map(|_| r.value()).collect::<Result<_>>()?;
+        program.bodies = (0..r.len()?).map(|_| Ok((r.u32()?, r.u32()?))).collect::<Result<_>>()?;
+        program.blocks = (0..r.len()?).map(|_| {
+            let mut block = Block::default();
+            for _ in 0..r.len()? {
+                block.code.push(r.op()?);
+                block.positions.push((r.len()?, r.len()?));
+            }
+            Ok(block)
+        }).collect::<Result<_>>()?;
+        let entry = r.u32()?;
+        if !r.bytes.is_empty() {
+            return error!(0, 0, ErrorKind::InvalidBytecode("unexpected bytes after the program".to_string()));
+        }
+        program.index()?;
+
+        let valid = (entry as usize) < program.blocks.len() && program.constants.iter().all(|val| match val {
+            Value::Quote(body) => valid_words(body, symbols.len(), builtins.len()),
+            _ => true,
+        }) && program.blocks.iter().all(|block| {
+            block.code.last() == Some(&Op::Return) && block.code.iter().all(|op| match *op {
+                Op::Push(c) => (c as usize) < program.constants.len(),
+                Op::Builtin(b) => (b as usize) < builtins.len(),
+                Op::Call(var) => (var as usize) < symbols.len(),
+                Op::Store(var, c) => (var as usize) < symbols.len() && matches!(program.constants.get(c as usize), Some(Value::Quote(_))),
+                Op::JumpUnless(target) | Op::Jump(target) => (target as usize) < block.code.len(),
+                Op::App | Op::If | Op::Return => true,
+            })
+        });
+        if !valid {
+            return error!(0, 0, ErrorKind::InvalidBytecode("an instruction refers to something that does not exist".to_string()));
+        }
+        Ok(Self {
+            name,
+            source,
+            first_line,
+            symbols,
+            builtins,
+            program,
+            entry,
+        })
+    }
+}
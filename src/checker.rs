@@ -0,0 +1,776 @@
+//! A static checker, inferring the stack effect of the code before it is evaluated.
+//!
+//! Each body gets an effect such as `( Integer -- Integer )`, from the signatures of the builtins. The part of the stack a body does not touch is a row variable, so that `app` and `if` can apply Quotes to any stack. Only definite errors are reported: a body that fails whatever the stack it is run on, or top-level code that fails on the current stack. An `if` whose branches disagree, unless its condition is a literal, or a call to a definition that is still being inferred, leaves the stack unknown rather than raising an error.
+
+use std::{collections::{HashMap, HashSet}, rc::Rc};
+use crate::{builtins::{underflow, Builtin}, eval::Value, parser::{Expr, ExprT}, Error, ErrorKind};
+
+/// The types a type variable may stand for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Class {
+    Any,
+    /// Integer, Real or String, as compared by `gt` and `lt`.
+    Ordered,
+    /// Integer or Real.
+    Number,
+}
+impl Class {
+    fn admits(self, r#type: &Type) -> bool {
+        match self {
+            Class::Any => true,
+            Class::Ordered => matches!(r#type, Type::Integer | Type::Real | Type::String),
+            Class::Number => matches!(r#type, Type::Integer | Type::Real),
+        }
+    }
+    fn names(self) -> &'static [&'static str] {
+        match self {
+            Class::Any => &["value"],
+            Class::Ordered => &["Integer", "Real", "String"],
+            Class::Number => &["Real", "Integer"],
+        }
+    }
+}
+
+/// The names of the types, as `Builtin::types` gives them.
+const TYPES: &[&str] = &["Integer", "Real", "String", "Symbol", "Quote"];
+
+#[derive(Clone, Debug)]
+enum Type {
+    Var(usize),
+    Integer,
+    Real,
+    String,
+    Symbol,
+    /// The effect of a Quote, generic if its variables are its own, as the ones of a literal are, and must be renamed at each use.
+    Quote(Rc<Effect>, bool),
+}
+impl Type {
+    fn name(&self) -> &'static str {
+        match self {
+            Type::Var(_) => "value",
+            Type::Integer => "Integer",
+            Type::Real => "Real",
+            Type::String => "String",
+            Type::Symbol => "Symbol",
+            Type::Quote(..) => "Quote",
+        }
+    }
+}
+
+/// A stack, its values bottom first, above the row variable `row`, or above nothing if it is `None`.
+#[derive(Clone, Debug)]
+struct Stack {
+    row: Option<usize>,
+    items: Vec<Type>,
+}
+impl Stack {
+    fn row(row: usize) -> Self {
+        Self { row: Some(row), items: vec![] }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Effect {
+    input: Stack,
+    output: Stack,
+}
+
+/// Why the inference stopped: an error, or a type it cannot represent, such as a Quote applying itself.
+enum Failure {
+    Error(Error),
+    Unknown,
+}
+type Inferred<T> = std::result::Result<T, Failure>;
+
+fn mismatch<T>(expected: &'static [&'static str], found: &Type) -> Inferred<T> {
+    Err(Failure::Error(Error::new(ErrorKind::TypeMismatch { expected, found: found.name().to_string() }, 0, 0)))
+}
+
+/// A change to the bindings, recorded to undo a failed unification.
+enum Change {
+    Type(usize),
+    Row(usize),
+    Class(usize, Option<Class>),
+}
+
+/// The bindings of the variables.
+#[derive(Default)]
+struct Bindings {
+    types: HashMap<usize, Type>,
+    rows: HashMap<usize, Stack>,
+    classes: HashMap<usize, Class>,
+    changes: Vec<Change>,
+}
+impl Bindings {
+    fn bind_type(&mut self, var: usize, r#type: Type) {
+        self.types.insert(var, r#type);
+        self.changes.push(Change::Type(var));
+    }
+    fn bind_row(&mut self, row: usize, stack: Stack) {
+        self.rows.insert(row, stack);
+        self.changes.push(Change::Row(row));
+    }
+    fn class(&self, var: usize) -> Class {
+        self.classes.get(&var).copied().unwrap_or(Class::Any)
+    }
+    /// Restricts `var` to the types of `class` too.
+    fn restrict(&mut self, var: usize, class: Class) {
+        let previous = self.classes.get(&var).copied();
+        if previous.unwrap_or(Class::Any) < class {
+            self.classes.insert(var, class);
+            self.changes.push(Change::Class(var, previous));
+        }
+    }
+    /// Undoes the changes made since `mark`, the number of changes then.
+    fn undo(&mut self, mark: usize) {
+        for change in self.changes.drain(mark..).rev() {
+            match change {
+                Change::Type(var) => {
+                    self.types.remove(&var);
+                }
+                Change::Row(row) => {
+                    self.rows.remove(&row);
+                }
+                Change::Class(var, Some(class)) => {
+                    self.classes.insert(var, class);
+                }
+                Change::Class(var, None) => {
+                    self.classes.remove(&var);
+                }
+            }
+        }
+    }
+}
+
+struct Checker<'a> {
+    bindings: Bindings,
+    next: usize,
+    builtins: &'a [Builtin],
+    symbols: &'a [String],
+    /// The bodies of the definitions whose effect can be known, having a single one.
+    bodies: HashMap<u16, Rc<[Expr]>>,
+    /// The definitions made by an earlier source, whose errors were already reported.
+    session: HashSet<u16>,
+    schemes: HashMap<u16, Option<Effect>>,
+    inferring: HashSet<u16>,
+    /// The number of bodies being inferred, inside one another.
+    depth: usize,
+    quiet: bool,
+    errors: Vec<Error>,
+}
+
+/// The maximum number of bodies inferred inside one another, past which a call or a Quote is left unknown, so that a long chain of definitions cannot overflow the native stack.
+const MAX_DEPTH: usize = 256;
+
+/// Collects the index and the body of every `let` in `exprs`, nested ones included, in order.
+fn stores(exprs: &[Expr], found: &mut Vec<(u16, Rc<[Expr]>)>) {
+    for expr in exprs {
+        match &expr.r#type {
+            ExprT::Quote(body) => stores(body, found),
+            ExprT::Store(idx, body) => {
+                found.push((*idx, body.clone()));
+                stores(body, found);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether the `if` ending `exprs` runs its first branch, if its condition and branches are literals.
+fn taken(exprs: &[Expr]) -> Option<bool> {
+    match exprs {
+        [.., cond, then, r#else, last] if matches!(last.r#type, ExprT::Builtin(idx) if Builtin::native_index("if") == Some(idx)) => match (&cond.r#type, &then.r#type, &r#else.r#type) {
+            (ExprT::Symbol(cond), ExprT::Quote(_), ExprT::Quote(_)) if cond == "t" || cond == "f" => Some(cond == "t"),
+            _ => None,
+        }
+        _ => None,
+    }
+}
+
+/// Checks `exprs` as evaluated on `stack`, or on any stack if it is `None`, `vars` holding the bodies of the definitions of the session.
+pub(crate) fn check(exprs: &[Expr], stack: Option<&[Value]>, vars: &[Option<Rc<[Expr]>>], builtins: &[Builtin], symbols: &[String]) -> Vec<Error> {
+    let mut found = vec![];
+    stores(exprs, &mut found);
+    let mut checker = Checker::new(vars, builtins, symbols);
+    for (idx, body) in &found {
+        let redefined = found.iter().filter(|(other, _)| other == idx).count() > 1 || checker.session.contains(idx);
+        if redefined {
+            // Calls may run either body, depending on whether the `let` was evaluated.
+            checker.bodies.remove(idx);
+            checker.session.remove(idx);
+        } else {
+            checker.bodies.insert(*idx, body.clone());
+        }
+    }
+    for (idx, body) in &found {
+        if checker.bodies.contains_key(idx) {
+            checker.scheme(*idx);
+        } else {
+            checker.body(body);
+        }
+    }
+    let mut current = match stack {
+        Some(stack) => Stack { row: None, items: stack.iter().map(|val| checker.value(val)).collect() },
+        None => Stack::row(checker.fresh()),
+    };
+    if let Err(Failure::Error(e)) = checker.run(&mut current, exprs) {
+        checker.errors.push(e);
+    }
+    let mut errors = checker.errors;
+    errors.sort_by_key(|e| (e.line, e.column));
+    errors.dedup_by_key(|e| (e.line, e.column));
+    errors.into_iter().map(|e| e.with_note("found before evaluation, from the stack effects of the words.")).collect()
+}
+
+/// The stack effect of the definition `idx` of the session, as `( Integer -- Integer )`, if it can be inferred.
+pub(crate) fn effect(idx: u16, vars: &[Option<Rc<[Expr]>>], builtins: &[Builtin], symbols: &[String]) -> Option<String> {
+    let mut checker = Checker::new(vars, builtins, symbols);
+    checker.scheme(idx).map(|effect| checker.render(&effect))
+}
+
+impl<'a> Checker<'a> {
+    fn new(vars: &[Option<Rc<[Expr]>>], builtins: &'a [Builtin], symbols: &'a [String]) -> Self {
+        let session = vars.iter().enumerate().filter_map(|(idx, body)| Some((idx as u16, body.clone()?))).collect::<HashMap<_, _>>();
+        Self {
+            bindings: Bindings::default(),
+            next: 0,
+            builtins,
+            symbols,
+            session: session.keys().copied().collect(),
+            bodies: session,
+            schemes: HashMap::new(),
+            inferring: HashSet::new(),
+            depth: 0,
+            quiet: false,
+            errors: vec![],
+        }
+    }
+    fn fresh(&mut self) -> usize {
+        self.next += 1;
+        self.next
+    }
+    fn unknown(&mut self) -> Effect {
+        Effect { input: Stack::row(self.fresh()), output: Stack::row(self.fresh()) }
+    }
+    fn value(&mut self, val: &Value) -> Type {
+        match val {
+            Value::Integer(_) => Type::Integer,
+            Value::Real(_) => Type::Real,
+            Value::String(_) => Type::String,
+            Value::Symbol(_) => Type::Symbol,
+            Value::Quote(_) => Type::Quote(Rc::new(self.unknown()), true),
+        }
+    }
+
+    /// The effect of the definition `idx`, inferred the first time, or `None` if it cannot be known.
+    fn scheme(&mut self, idx: u16) -> Option<Effect> {
+        if let Some(scheme) = self.schemes.get(&idx) {
+            return scheme.clone();
+        }
+        let body = self.bodies.get(&idx)?.clone();
+        if self.depth >= MAX_DEPTH {
+            // Left to be inferred when it is met less deep.
+            return None;
+        }
+        if !self.inferring.insert(idx) {
+            // A recursive call: the effect is not known yet.
+            return None;
+        }
+        let quiet = self.quiet;
+        self.quiet = quiet || self.session.contains(&idx);
+        let scheme = self.body(&body).map(|effect| self.resolve_effect(&effect));
+        self.quiet = quiet;
+        self.inferring.remove(&idx);
+        self.schemes.insert(idx, scheme.clone());
+        scheme
+    }
+    /// Infers the effect of a body, reporting its error if it has one, or `None` if it is nested too deep.
+    fn body(&mut self, exprs: &[Expr]) -> Option<Effect> {
+        if self.depth >= MAX_DEPTH {
+            return None;
+        }
+        let input = Stack::row(self.fresh());
+        let mut stack = input.clone();
+        self.depth += 1;
+        let result = self.run(&mut stack, exprs);
+        self.depth -= 1;
+        match result {
+            Ok(()) => Some(Effect { input, output: stack }),
+            Err(Failure::Error(e)) => {
+                if !self.quiet {
+                    self.errors.push(e);
+                }
+                None
+            }
+            Err(Failure::Unknown) => None,
+        }
+    }
+    fn run(&mut self, stack: &mut Stack, exprs: &[Expr]) -> Inferred<()> {
+        for (i, expr) in exprs.iter().enumerate() {
+            let result = match taken(&exprs[..=i]) {
+                Some(then) => self.taken(stack, then),
+                None => self.expr(stack, expr),
+            };
+            result.map_err(|failure| match failure {
+                Failure::Error(e) if e.line == 0 => Failure::Error(Error { line: expr.line, column: expr.column, ..e }),
+                failure => failure,
+            })?;
+        }
+        Ok(())
+    }
+    fn expr(&mut self, stack: &mut Stack, expr: &Expr) -> Inferred<()> {
+        let r#type = match &expr.r#type {
+            ExprT::Integer(_) => Type::Integer,
+            ExprT::Real(_) => Type::Real,
+            ExprT::String(_) => Type::String,
+            ExprT::Symbol(_) => Type::Symbol,
+            ExprT::Quote(body) => match self.body(body) {
+                Some(effect) => Type::Quote(Rc::new(self.resolve_effect(&effect)), true),
+                None => Type::Quote(Rc::new(self.unknown()), true),
+            }
+            // The bodies of the definitions are checked on their own.
+            ExprT::Store(..) => return Ok(()),
+            ExprT::Var(idx) => return match self.scheme(*idx) {
+                Some(scheme) => {
+                    let effect = self.instantiate(&scheme);
+                    let (needed, held) = (self.flatten(&effect.input).items.len(), self.flatten(stack));
+                    if held.row.is_none() && held.items.len() < needed {
+                        let name = self.symbols.get(*idx as usize).map_or("", String::as_str);
+                        return Err(Failure::Error(underflow(name, needed, held.items.len(), 0, 0)));
+                    }
+                    self.unify_stacks(&effect.input, stack)?;
+                    *stack = effect.output;
+                    Ok(())
+                }
+                None => {
+                    *stack = Stack::row(self.fresh());
+                    Ok(())
+                }
+            },
+            ExprT::Builtin(idx) => return self.builtin(stack, *idx as usize),
+        };
+        stack.items.push(r#type);
+        Ok(())
+    }
+    /// Applies the effect of a builtin, derived from its registry entry.
+    ///
+    /// Its values have the types named by `Builtin::types`: one per value for `Symbol-Quote-Quote`, and one for all of them for `Integer/Real`, which must then have the same type, `Any` accepting anything. An output named as an input is the same value, a `bool` is a Symbol, and another one has the type of the inputs. `...` applies the Quotes among the inputs to the rest of the stack, one of them if there are two, and leaves it unknown if there is none, as host functions do.
+    fn builtin(&mut self, stack: &mut Stack, idx: usize) -> Inferred<()> {
+        let builtins = self.builtins;
+        let builtin = &builtins[idx];
+        *stack = self.flatten(stack);
+        if stack.row.is_none() && stack.items.len() < builtin.arity() {
+            return Err(Failure::Error(underflow(&builtin.name, builtin.arity(), stack.items.len(), 0, 0)));
+        }
+        let mut inputs = (0..builtin.arity()).map(|_| self.pop(stack)).collect::<Inferred<Vec<_>>>()?;
+        inputs.reverse();
+        let per_value = builtin.types.contains('-');
+        let accepted = builtin.types.split(if per_value { '-' } else { '/' }).collect::<Vec<_>>();
+        let shared = !per_value && accepted.iter().all(|name| TYPES.contains(name));
+        let mut quotes = vec![];
+        for (i, (input, name)) in inputs.iter().zip(&builtin.inputs).enumerate() {
+            let types = if per_value { accepted.get(i..=i).unwrap_or_default() } else { &accepted[..] };
+            if types == ["Quote"] {
+                quotes.push(input.clone());
+            } else if name == "bool" {
+                self.unify(&Type::Symbol, input)?;
+            } else if shared && i > 0 {
+                self.unify(&inputs[0], input)?;
+            } else {
+                self.accept(input, types)?;
+            }
+        }
+        if builtin.outputs == ["..."] {
+            match &quotes[..] {
+                [quote] => {
+                    let (effect, output) = self.applied(stack);
+                    self.apply(quote, &effect)?;
+                    *stack = output;
+                }
+                [then, r#else] => self.branches(stack, then, r#else)?,
+                _ => *stack = Stack::row(self.fresh()),
+            }
+            return Ok(());
+        }
+        for output in &builtin.outputs {
+            let r#type = match builtin.inputs.iter().position(|input| input == output) {
+                Some(i) => inputs[i].clone(),
+                None if output == "bool" => Type::Symbol,
+                None if shared && !inputs.is_empty() => inputs[0].clone(),
+                None => Type::Var(self.fresh()),
+            };
+            stack.items.push(r#type);
+        }
+        Ok(())
+    }
+    /// Restricts `val` to the types named by `types`, if they are a type or a class.
+    fn accept(&mut self, val: &Type, types: &[&str]) -> Inferred<()> {
+        match types {
+            ["Integer"] => self.unify(&Type::Integer, val),
+            ["Real"] => self.unify(&Type::Real, val),
+            ["String"] => self.unify(&Type::String, val),
+            ["Symbol"] => self.unify(&Type::Symbol, val),
+            _ => {
+                let mut types = types.to_vec();
+                types.sort_unstable();
+                for class in [Class::Number, Class::Ordered] {
+                    let mut names = class.names().to_vec();
+                    names.sort_unstable();
+                    if names == types {
+                        return self.constrain(val, class);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+    /// The effect of a Quote applied to `stack`, and the stack it leaves.
+    fn applied(&mut self, stack: &Stack) -> (Type, Stack) {
+        let output = Stack::row(self.fresh());
+        (Type::Quote(Rc::new(Effect { input: stack.clone(), output: output.clone() }), false), output)
+    }
+    /// Unifies a value to be applied with the effect it is applied with.
+    fn apply(&mut self, quote: &Type, effect: &Type) -> Inferred<()> {
+        match self.resolve(quote) {
+            Type::Var(_) => self.unify(effect, quote),
+            quote @ Type::Quote(..) => self.unify(&quote, effect),
+            other => mismatch(&["Quote"], &other),
+        }
+    }
+    /// Applies one of two Quotes to `stack`.
+    ///
+    /// A branch that always fails is assumed not to be taken. If both succeed but leave different stacks, the stack is left unknown.
+    fn branches(&mut self, stack: &mut Stack, then: &Type, r#else: &Type) -> Inferred<()> {
+        let (effect, output) = self.applied(stack);
+        let mark = self.bindings.changes.len();
+        let then_result = self.apply(then, &effect);
+        self.bindings.undo(mark);
+        let else_result = self.apply(r#else, &effect);
+        self.bindings.undo(mark);
+        // Applying again a branch that succeeded alone succeeds again.
+        let result = match (then_result, else_result) {
+            (Ok(()), Ok(())) => self.apply(r#else, &effect).and_then(|_| self.apply(then, &effect)),
+            (Ok(()), Err(Failure::Error(_))) => self.apply(then, &effect),
+            (Err(Failure::Error(_)), Ok(())) => self.apply(r#else, &effect),
+            (Err(Failure::Error(e)), Err(Failure::Error(_))) => return Err(Failure::Error(e)),
+            _ => Err(Failure::Unknown),
+        };
+        *stack = match result {
+            Ok(()) => output,
+            Err(_) => {
+                self.bindings.undo(mark);
+                Stack::row(self.fresh())
+            }
+        };
+        Ok(())
+    }
+
+    /// Applies the branch of an `if` whose condition is a literal, the other one never being run.
+    fn taken(&mut self, stack: &mut Stack, then: bool) -> Inferred<()> {
+        let r#else = self.pop(stack)?;
+        let first = self.pop(stack)?;
+        self.pop(stack)?;
+        let branch = if then { first } else { r#else };
+        let (effect, output) = self.applied(stack);
+        self.apply(&branch, &effect)?;
+        *stack = output;
+        Ok(())
+    }
+
+    /// Follows the bindings of a type variable.
+    fn resolve(&self, r#type: &Type) -> Type {
+        let mut r#type = r#type;
+        while let Type::Var(var) = r#type {
+            match self.bindings.types.get(var) {
+                Some(bound) => r#type = bound,
+                None => break,
+            }
+        }
+        r#type.clone()
+    }
+    /// Follows the bindings of the row variable of `stack`, so that it is unbound.
+    fn flatten(&self, stack: &Stack) -> Stack {
+        let mut stack = stack.clone();
+        while let Some(bound) = stack.row.and_then(|row| self.bindings.rows.get(&row)) {
+            let mut items = bound.items.clone();
+            items.append(&mut stack.items);
+            stack = Stack { row: bound.row, items };
+        }
+        stack
+    }
+    fn resolve_type(&self, r#type: &Type) -> Type {
+        match self.resolve(r#type) {
+            Type::Quote(effect, generic) => Type::Quote(Rc::new(self.resolve_effect(&effect)), generic),
+            r#type => r#type,
+        }
+    }
+    fn resolve_stack(&self, stack: &Stack) -> Stack {
+        let stack = self.flatten(stack);
+        Stack { row: stack.row, items: stack.items.iter().map(|r#type| self.resolve_type(r#type)).collect() }
+    }
+    /// `effect` without bound variables.
+    fn resolve_effect(&self, effect: &Effect) -> Effect {
+        Effect { input: self.resolve_stack(&effect.input), output: self.resolve_stack(&effect.output) }
+    }
+    /// A copy of a resolved effect, with new variables.
+    fn instantiate(&mut self, effect: &Effect) -> Effect {
+        let mut renamed = HashMap::new();
+        self.rename_effect(effect, &mut renamed)
+    }
+    fn rename(&mut self, var: usize, renamed: &mut HashMap<usize, usize>) -> usize {
+        if let Some(new) = renamed.get(&var) {
+            return *new;
+        }
+        let new = self.fresh();
+        let class = self.bindings.class(var);
+        self.bindings.restrict(new, class);
+        renamed.insert(var, new);
+        new
+    }
+    fn rename_effect(&mut self, effect: &Effect, renamed: &mut HashMap<usize, usize>) -> Effect {
+        Effect { input: self.rename_stack(&effect.input, renamed), output: self.rename_stack(&effect.output, renamed) }
+    }
+    fn rename_stack(&mut self, stack: &Stack, renamed: &mut HashMap<usize, usize>) -> Stack {
+        Stack {
+            row: stack.row.map(|row| self.rename(row, renamed)),
+            items: stack.items.iter().map(|r#type| match r#type {
+                Type::Var(var) => Type::Var(self.rename(*var, renamed)),
+                Type::Quote(effect, generic) => Type::Quote(Rc::new(self.rename_effect(effect, renamed)), *generic),
+                r#type => r#type.clone(),
+            }).collect(),
+        }
+    }
+
+    /// Pops a value, taking it from the row variable if the stack holds none.
+    fn pop(&mut self, stack: &mut Stack) -> Inferred<Type> {
+        *stack = self.flatten(stack);
+        if let Some(r#type) = stack.items.pop() {
+            return Ok(r#type);
+        }
+        match stack.row {
+            Some(row) => {
+                let (below, var) = (self.fresh(), self.fresh());
+                self.bindings.bind_row(row, Stack { row: Some(below), items: vec![Type::Var(var)] });
+                *stack = Stack::row(below);
+                Ok(Type::Var(var))
+            }
+            None => Err(Failure::Error(Error::new(ErrorKind::StackUnderflow, 0, 0))),
+        }
+    }
+    fn constrain(&mut self, r#type: &Type, class: Class) -> Inferred<()> {
+        match self.resolve(r#type) {
+            Type::Var(var) => {
+                self.bindings.restrict(var, class);
+                Ok(())
+            }
+            r#type if class.admits(&r#type) => Ok(()),
+            r#type => mismatch(class.names(), &r#type),
+        }
+    }
+    fn occurs(&self, var: usize, r#type: &Type) -> bool {
+        match self.resolve(r#type) {
+            Type::Var(other) => other == var,
+            Type::Quote(effect, _) => self.occurs_in(var, &effect.input) || self.occurs_in(var, &effect.output),
+            _ => false,
+        }
+    }
+    fn occurs_in(&self, var: usize, stack: &Stack) -> bool {
+        let stack = self.flatten(stack);
+        stack.row == Some(var) || stack.items.iter().any(|r#type| self.occurs(var, r#type))
+    }
+    fn bind(&mut self, var: usize, r#type: Type) -> Inferred<()> {
+        let class = self.bindings.class(var);
+        match &r#type {
+            Type::Var(other) => self.bindings.restrict(*other, class),
+            _ if !class.admits(&r#type) => return mismatch(class.names(), &r#type),
+            _ if self.occurs(var, &r#type) => return Err(Failure::Unknown),
+            _ => {}
+        }
+        self.bindings.bind_type(var, r#type);
+        Ok(())
+    }
+    /// Unifies the type a word expects with the one it is given.
+    fn unify(&mut self, expected: &Type, found: &Type) -> Inferred<()> {
+        let (expected, found) = (self.generic(expected), self.generic(found));
+        match (&expected, &found) {
+            (Type::Var(var), Type::Var(other)) if var == other => Ok(()),
+            (Type::Var(var), _) => self.bind(*var, found),
+            (_, Type::Var(var)) => self.bind(*var, expected),
+            (Type::Integer, Type::Integer) | (Type::Real, Type::Real) | (Type::String, Type::String) | (Type::Symbol, Type::Symbol) => Ok(()),
+            (Type::Quote(expected, _), Type::Quote(found, _)) => {
+                self.unify_stacks(&expected.input, &found.input)?;
+                self.unify_stacks(&expected.output, &found.output)
+            }
+            _ => mismatch(match expected.name() {
+                "Integer" => &["Integer"],
+                "Real" => &["Real"],
+                "String" => &["String"],
+                "Symbol" => &["Symbol"],
+                _ => &["Quote"],
+            }, &found),
+        }
+    }
+    /// The resolved `type`, the effect of a generic Quote being given new variables.
+    fn generic(&mut self, r#type: &Type) -> Type {
+        match self.resolve(r#type) {
+            Type::Quote(effect, true) => Type::Quote(Rc::new(self.instantiate(&effect)), false),
+            r#type => r#type,
+        }
+    }
+    fn unify_stacks(&mut self, expected: &Stack, found: &Stack) -> Inferred<()> {
+        let (mut expected, mut found) = (self.flatten(expected), self.flatten(found));
+        while !expected.items.is_empty() && !found.items.is_empty() {
+            if let (Some(lhs), Some(rhs)) = (expected.items.pop(), found.items.pop()) {
+                self.unify(&lhs, &rhs)?;
+            }
+            (expected, found) = (self.flatten(&expected), self.flatten(&found));
+        }
+        match (expected.row, found.row) {
+            _ if expected.items.is_empty() && found.items.is_empty() && expected.row == found.row => Ok(()),
+            (Some(row), _) if expected.items.is_empty() => self.bind_row(row, found),
+            (_, Some(row)) if found.items.is_empty() => self.bind_row(row, expected),
+            _ => Err(Failure::Error(Error::new(ErrorKind::StackUnderflow, 0, 0))),
+        }
+    }
+    fn bind_row(&mut self, row: usize, stack: Stack) -> Inferred<()> {
+        if stack.row == Some(row) && stack.items.is_empty() {
+            return Ok(());
+        }
+        if self.occurs_in(row, &stack) {
+            return Err(Failure::Unknown);
+        }
+        self.bindings.bind_row(row, stack);
+        Ok(())
+    }
+
+    /// Renders a resolved effect, leaving out the row variable below both sides when it appears nowhere else.
+    fn render(&self, effect: &Effect) -> String {
+        let mut names = Names::default();
+        names.collect_effect(effect, self);
+        let hidden = match (effect.input.row, effect.output.row) {
+            (Some(input), Some(output)) if input == output && names.rows[&input].1 == 2 => Some(input),
+            _ => None,
+        };
+        format!("( {} )", names.effect(effect, hidden, self))
+    }
+}
+
+/// The names of the variables of an effect being rendered: `A` for rows, `a` for types, and the class for a constrained type.
+#[derive(Default)]
+struct Names {
+    /// The name and the number of occurrences of each row variable.
+    rows: HashMap<usize, (String, usize)>,
+    types: HashMap<usize, String>,
+    /// The constrained type variables of each class, in order.
+    classes: HashMap<Class, Vec<usize>>,
+}
+impl Names {
+    fn collect_effect(&mut self, effect: &Effect, checker: &Checker) {
+        self.collect_stack(&effect.input, checker);
+        self.collect_stack(&effect.output, checker);
+    }
+    fn collect_stack(&mut self, stack: &Stack, checker: &Checker) {
+        if let Some(row) = stack.row {
+            let next = self.rows.len();
+            let entry = self.rows.entry(row).or_insert_with(|| (((b'A' + (next % 26) as u8) as char).to_string(), 0));
+            entry.1 += 1;
+        }
+        for r#type in &stack.items {
+            match r#type {
+                Type::Var(var) => match checker.bindings.class(*var) {
+                    Class::Any => {
+                        let next = self.types.len();
+                        self.types.entry(*var).or_insert_with(|| ((b'a' + (next % 26) as u8) as char).to_string());
+                    }
+                    class => {
+                        let vars = self.classes.entry(class).or_default();
+                        if !vars.contains(var) {
+                            vars.push(*var);
+                        }
+                    }
+                }
+                Type::Quote(effect, _) => self.collect_effect(effect, checker),
+                _ => {}
+            }
+        }
+    }
+    fn effect(&self, effect: &Effect, hidden: Option<usize>, checker: &Checker) -> String {
+        let (input, output) = (self.stack(&effect.input, hidden, checker), self.stack(&effect.output, hidden, checker));
+        [input, "--".to_string(), output].iter().filter(|part| !part.is_empty()).cloned().collect::<Vec<_>>().join(" ")
+    }
+    fn stack(&self, stack: &Stack, hidden: Option<usize>, checker: &Checker) -> String {
+        let row = stack.row.filter(|row| Some(*row) != hidden).map(|row| self.rows[&row].0.clone());
+        row.into_iter().chain(stack.items.iter().map(|r#type| self.r#type(r#type, checker))).collect::<Vec<_>>().join(" ")
+    }
+    fn r#type(&self, r#type: &Type, checker: &Checker) -> String {
+        match r#type {
+            Type::Var(var) => match checker.bindings.class(*var) {
+                Class::Any => self.types[var].clone(),
+                class => {
+                    let vars = &self.classes[&class];
+                    let name = if class == Class::Number { "Number" } else { "Ordered" };
+                    match vars.iter().position(|other| other == var) {
+                        Some(idx) if vars.len() > 1 => format!("{}{}", name, idx + 1),
+                        _ => name.to_string(),
+                    }
+                }
+            }
+            Type::Quote(effect, _) => format!("[ {} ]", self.effect(effect, None, checker)),
+            r#type => r#type.name().to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Interpreter;
+
+    /// An interpreter checking the code as written.
+    fn interpreter() -> Interpreter {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(false);
+        interpreter
+    }
+
+    #[test]
+    fn long_chains_of_calls() {
+        let mut interpreter = interpreter();
+        let chain = (0..5000).map(|i| format!("let d{} ( d{} )\n", i, i + 1)).collect::<String>();
+        let exprs = interpreter.parse(format!("{}let d5000 ( \"x\" ) d0 1 +", chain)).expect("the chain should parse");
+        assert!(interpreter.check(&exprs).is_empty());
+    }
+
+    /// The positions of the errors found in `src`, checked on an empty stack.
+    fn errors(src: &str) -> Vec<(usize, usize)> {
+        let mut interpreter = interpreter();
+        let exprs = interpreter.parse(src).expect("the source should parse");
+        interpreter.check(&exprs).iter().map(|e| (e.line, e.column)).collect()
+    }
+
+    #[test]
+    fn effects() {
+        let mut interpreter = interpreter();
+        interpreter.eval_str("let square ( dup * ) let ap ( app ) let both ( [1] [\"a\"] if )").unwrap();
+        assert_eq!(interpreter.effect("square").as_deref(), Some("( Number -- Number )"));
+        assert_eq!(interpreter.effect("ap").as_deref(), Some("( A [ A -- B ] -- B )"));
+        assert_eq!(interpreter.effect("both").as_deref(), Some("( A Symbol -- B )"));
+    }
+
+    #[test]
+    fn definite_errors() {
+        assert_eq!(errors("1 [2] swap app"), [(1, 12)]);
+        assert_eq!(errors("#f [1 2] [3] if +"), [(1, 17)]);
+        assert_eq!(errors("let sq ( dup * ) \"x\" sq"), [(1, 22)]);
+    }
+
+    #[test]
+    fn possible_errors() {
+        // Either branch may run.
+        assert!(errors("1 2 lt [1] [\"a\"] if 1 +").is_empty());
+        // A recursive call is not inferred while its definition is.
+        assert!(errors("let f ( dup 0 eq [pop \"x\"] [1 - f] if ) 3 f \"y\" cat").is_empty());
+        assert!(errors("let g ( g ) 1 g +").is_empty());
+        // A call may run either body, depending on whether the second `let` was evaluated.
+        assert!(errors("let g ( 1 ) [let g ( \"a\" )] pop g 1 +").is_empty());
+    }
+}
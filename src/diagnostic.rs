@@ -0,0 +1,54 @@
+use crate::{lexer::{is_finishing, string_end}, Error};
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// The number of characters of the token starting at `column` (1-based) in `line`.
+fn token_width(line: &str, column: usize) -> usize {
+    let start = line.char_indices().nth(column.saturating_sub(1)).map_or(line.len(), |(idx, _)| idx);
+    let rest = &line[start..];
+    match rest.chars().next() {
+        None => 1,
+        Some('"') => string_end(rest, 0).map_or(rest.chars().count(), |end| rest[..end].chars().count()),
+        Some('\\') => 2,
+        Some(c) if is_finishing(c) => 1,
+        _ => rest.chars().take_while(|c| !is_finishing(*c)).count(),
+    }
+}
+
+/// Renders `e` with the line of `source` it points to, `source` being `name` and starting at `first_line`.
+pub(crate) fn render(e: &Error, source: Option<(&str, usize, &str)>, colour: bool) -> String {
+    let paint = |style: &str, text: &str| if colour { format!("{}{}{}", style, text, RESET) } else { text.to_string() };
+    let mut out = format!("{}{}\n", paint(RED, &format!("error[{}]", e.code())), paint(BOLD, &format!(": {}", e.kind)));
+
+    let snippet = source.and_then(|(name, first_line, text)| {
+        let local = e.line.checked_sub(first_line)?;
+        Some((name, local + 1, text.lines().nth(local).unwrap_or("")))
+    });
+    let gutter = match snippet {
+        Some((name, line, text)) => {
+            let gutter = " ".repeat(line.to_string().len());
+            let padding = text.chars().take(e.column.saturating_sub(1)).map(|c| if c == '\t' { '\t' } else { ' ' }).collect::<String>();
+            out.push_str(&format!("{}{} {}:{}:{}\n", gutter, paint(BLUE, "-->"), name, line, e.column));
+            out.push_str(&format!("{} {}\n", gutter, paint(BLUE, "|")));
+            out.push_str(&format!("{} {}\n", paint(BLUE, &format!("{} |", line)), text));
+            out.push_str(&format!("{} {} {}{}\n", gutter, paint(BLUE, "|"), padding, paint(RED, &"^".repeat(token_width(text, e.column)))));
+            gutter
+        }
+        None if e.line != 0 => {
+            out.push_str(&format!("{} {}:{}\n", paint(BLUE, "-->"), e.line, e.column));
+            String::new()
+        }
+        None => String::new(),
+    };
+    for note in &e.notes {
+        let (kind, note) = match note.strip_prefix("help: ") {
+            Some(help) => ("help", help),
+            None => ("note", note.as_str()),
+        };
+        out.push_str(&format!("{} {} {}: {}\n", gutter, paint(BLUE, "="), paint(BOLD, kind), note));
+    }
+    out
+}
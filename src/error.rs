@@ -0,0 +1,158 @@
+use std::fmt;
+
+/// A limit set by `eval::Quotas`, or by the parser for `Nesting`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quota {
+    StackDepth,
+    StringBytes,
+    QuoteSize,
+    /// The depth of the brackets, see `parser::MAX_NESTING`.
+    Nesting,
+}
+
+/// What went wrong, with the data needed to describe it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    UnexpectedEof,
+    /// A bracket where none can be.
+    UnexpectedToken(String),
+    /// A missing bracket or name, `expected` being its description.
+    Expected { expected: String, found: String },
+    StackUnderflow,
+    /// A value of type `found` where one of the `expected` types was needed.
+    TypeMismatch { expected: &'static [&'static str], found: String },
+    UndefinedWord(String),
+    /// A definition used before its `let` was evaluated, by index.
+    UnevaluatedVariable(u16),
+    InvalidNumber(String),
+    /// A Real literal missing digits, `expected` saying where.
+    InvalidReal { literal: String, expected: &'static str },
+    /// A number literal that does not fit its `type`.
+    OutOfRange { literal: String, r#type: &'static str },
+    InvalidEscape(char),
+    InvalidUnicodeEscape(String),
+    /// A Symbol other than `#t` and `#f` where a boolean was needed.
+    ExpectedBoolean(String),
+    DivisionByZero,
+    IntegerOverflow,
+    /// More nested calls than the limit.
+    CallDepthExceeded(usize),
+    /// The fuel of the evaluator ran out.
+    OutOfFuel,
+    /// The deadline of the evaluator has passed.
+    DeadlineExceeded,
+    QuotaExceeded { quota: Quota, limit: usize },
+    /// A `.ktc` file that cannot be loaded, and why.
+    InvalidBytecode(String),
+    RecursiveHostCall(String),
+    DuplicateBuiltin(String),
+    InvalidSession,
+    /// An error raised by a host builtin.
+    Custom(String),
+}
+impl ErrorKind {
+    /// The stable code of the error, listed in the README.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnexpectedEof => "E0001",
+            Self::UnexpectedToken(_) | Self::Expected { .. } => "E0002",
+            Self::StackUnderflow => "E0003",
+            Self::TypeMismatch { .. } => "E0004",
+            Self::UndefinedWord(_) | Self::UnevaluatedVariable(_) => "E0005",
+            Self::InvalidNumber(_) | Self::InvalidReal { .. } | Self::OutOfRange { .. } | Self::InvalidEscape(_) | Self::InvalidUnicodeEscape(_) => "E0006",
+            Self::ExpectedBoolean(_) => "E0007",
+            Self::RecursiveHostCall(_) => "E0008",
+            Self::DuplicateBuiltin(_) => "E0009",
+            Self::InvalidSession => "E0010",
+            Self::DivisionByZero => "E0011",
+            Self::IntegerOverflow => "E0012",
+            Self::Custom(_) => "E0013",
+            Self::CallDepthExceeded(_) => "E0014",
+            Self::OutOfFuel => "E0015",
+            Self::DeadlineExceeded => "E0016",
+            Self::QuotaExceeded { .. } => "E0017",
+            Self::InvalidBytecode(_) => "E0018",
+        }
+    }
+}
+
+fn article(r#type: &str) -> String {
+    let an = r#type.starts_with(['A', 'E', 'I', 'O', 'U']);
+    format!("{} {}", if an { "an" } else { "a" }, r#type)
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof => write!(f, "Unexpected EOF while parsing."),
+            Self::UnexpectedToken(token) => write!(f, "Unexpected '{}'.", token),
+            Self::Expected { expected, found } => write!(f, "Expected {}, found '{}'", expected, found),
+            Self::StackUnderflow => write!(f, "Stack underflow."),
+            Self::TypeMismatch { expected, found } => {
+                let expected = expected.iter().map(|r#type| article(r#type)).collect::<Vec<_>>();
+                write!(f, "Expected {}, found {}.", expected.join(" or "), article(found))
+            }
+            Self::UndefinedWord(word) => write!(f, "Use of an undefined variable: {}.", word),
+            Self::UnevaluatedVariable(idx) => write!(f, "Use of a variable that has not been evaluated yet: ${}.", idx),
+            Self::InvalidNumber(literal) => write!(f, "Invalid number literal: {}.", literal),
+            Self::InvalidReal { literal, expected } => write!(f, "Invalid Real literal: {}, expected {}.", literal, expected),
+            Self::OutOfRange { literal, r#type: "Integer" } => write!(f, "Integer literal out of range: {}, Integers are between {} and {}.", literal, i32::MIN, i32::MAX),
+            Self::OutOfRange { literal, r#type } => write!(f, "{} literal out of range: {}.", r#type, literal),
+            Self::InvalidEscape(c) => write!(f, "Invalid escape sequence: \\{}.", c),
+            Self::InvalidUnicodeEscape(code) => write!(f, "Invalid unicode escape: \\u{{{}}}.", code),
+            Self::ExpectedBoolean(sym) => write!(f, "Expected #t or #f, found #{}.", sym),
+            Self::DivisionByZero => write!(f, "Division by zero."),
+            Self::IntegerOverflow => write!(f, "Integer overflow."),
+            Self::CallDepthExceeded(limit) => write!(f, "Call depth limit exceeded: more than {} nested calls.", limit),
+            Self::OutOfFuel => write!(f, "Out of fuel."),
+            Self::DeadlineExceeded => write!(f, "Deadline exceeded."),
+            Self::InvalidBytecode(reason) => write!(f, "Invalid compiled file: {}.", reason),
+            Self::QuotaExceeded { quota: Quota::StackDepth, limit } => write!(f, "Stack depth quota exceeded: the stack can hold at most {} values.", limit),
+            Self::QuotaExceeded { quota: Quota::StringBytes, limit } => write!(f, "String quota exceeded: the Strings on the stack can take at most {} bytes.", limit),
+            Self::QuotaExceeded { quota: Quota::QuoteSize, limit } => write!(f, "Quote size quota exceeded: a Quote can hold at most {} expressions.", limit),
+            Self::QuotaExceeded { quota: Quota::Nesting, limit } => write!(f, "Nesting quota exceeded: brackets can be nested at most {} deep.", limit),
+            Self::RecursiveHostCall(name) => write!(f, "Recursive call to the host builtin {}.", name),
+            Self::DuplicateBuiltin(name) => write!(f, "A builtin named {} already exists.", name),
+            Self::InvalidSession => write!(f, "Not a session file, expected '{}' as the first line.", crate::SESSION_HEADER),
+            Self::Custom(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// An error, located by its 1-based line and column, or by `0, 0` when it has no position.
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ErrorKind,
+    pub notes: Vec<String>,
+}
+impl Error {
+    pub fn new(kind: ErrorKind, line: usize, column: usize) -> Self {
+        Self {
+            line,
+            column,
+            kind,
+            notes: vec![],
+        }
+    }
+    /// Adds a note, printed under the error. Notes starting with `help: ` are printed as hints.
+    pub fn with_note(mut self, note: impl ToString) -> Self {
+        self.notes.push(note.to_string());
+        self
+    }
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "error[{}]: {}", self.code(), self.kind)
+        } else {
+            write!(f, "{}:{}: error[{}]: {}", self.line, self.column, self.code(), self.kind)
+        }
+    }
+}
+impl std::error::Error for Error {}
@@ -1,11 +1,12 @@
-use crate::{parser::{Expr, ExprT}, Result, Error, error};
+use std::{rc::Rc, time::Instant};
+use crate::{lexer::escape, parser::{Expr, ExprT}, builtins::Builtin, bytecode::Program, Result, Error, ErrorKind, Quota, error};
 
 #[derive(Clone, Debug)]
 pub enum Value {
     Integer(i32),
     Real(f32),
     String(String),
-    Quote(Vec<Expr>),
+    Quote(Rc<[Expr]>),
     Symbol(String),
 }
 impl Value {
@@ -22,17 +23,91 @@ impl Value {
         match self {
             Value::Integer(z) => format!("{}", z),
             Value::Real(r) => format!("{}", r),
-            Value::String(s) => format!("{}{}{}", if quotes { "\x1b[0;32m\"" } else { "" }, s, if quotes { "\"\x1b[0m" } else { "" }),
+            Value::String(s) => if quotes {
+                format!("\x1b[0;32m\"{}\"\x1b[0m", escape(&s))
+            } else {
+                s
+            }
             Value::Symbol(sym) => format!("#{}", sym),
-            Value::Quote(content) => format!("[{}]", content.into_iter().map(|e| e.r#type.get_lit()).collect::<Vec<String>>().join(" ")),
+            Value::Quote(content) => format!("[{}]", content.iter().map(|e| e.r#type.clone().get_lit()).collect::<Vec<String>>().join(" ")),
+        }
+    }
+}
+pub type HostFn = Box<dyn FnMut(&mut Evaluator) -> Result<()>>;
+
+/// The default limit of nested calls, see `Evaluator::max_depth`.
+pub const DEFAULT_MAX_DEPTH: usize = 100_000;
+
+/// The number of steps between two checks of the deadline, as reading the clock is slower than a step.
+const DEADLINE_PERIOD: u64 = 1024;
+
+/// Limits on the memory taken by the values, `None` meaning no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Quotas {
+    /// The number of values on the stack.
+    pub stack_depth: Option<usize>,
+    /// The total length in bytes of the Strings on the stack.
+    pub string_bytes: Option<usize>,
+    /// The number of expressions of a Quote, nested ones included, checked when the Quote is parsed or loaded from a `.ktc` file.
+    pub quote_size: Option<usize>,
+}
+
+/// The number of expressions of `exprs`, counting the ones of nested quotes and definitions.
+pub(crate) fn size(exprs: &[Expr]) -> usize {
+    exprs.iter().map(|expr| match &expr.r#type {
+        ExprT::Quote(content) | ExprT::Store(_, content) => 1 + size(content),
+        _ => 1,
+    }).sum()
+}
+
+/// Reports the Quote literals of `exprs` holding more than `max` expressions, without the ones nested in a reported one.
+pub(crate) fn oversized(exprs: &[Expr], max: usize, errors: &mut Vec<Error>) {
+    for expr in exprs {
+        match &expr.r#type {
+            ExprT::Quote(content) if size(content) > max => errors.push(Error::new(ErrorKind::QuotaExceeded { quota: Quota::QuoteSize, limit: max }, expr.line, expr.column)),
+            ExprT::Store(_, content) => oversized(content, max, errors),
+            _ => {}
         }
     }
 }
+
+/// How the evaluator runs the expressions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// Walks the expressions themselves, kept to check the VM against.
+    TreeWalker,
+    /// Compiles the bodies to bytecode the first time they are called, and runs it on the VM.
+    #[default]
+    Bytecode,
+}
+
+/// A body being evaluated, `pc` being the index of its next expression.
+pub(crate) struct Frame {
+    body: Rc<[Expr]>,
+    pc: usize,
+}
+
 pub struct Evaluator {
     pub stack: Vec<Value>,
-    vars: Vec<Vec<Expr>>,
+    /// The definition bodies, by symbol index, `None` until their `let` is evaluated.
+    pub(crate) vars: Vec<Option<Rc<[Expr]>>>,
     input: Vec<Expr>,
-    builtins: Vec<fn(&mut Evaluator, usize, usize) -> Result<()>>
+    pub(crate) builtins: Vec<Builtin>,
+    /// The bodies being evaluated by the tree-walker, innermost last, standing for the native call stack.
+    pub(crate) frames: Vec<Frame>,
+    /// The maximum number of nested calls, tail calls excepted.
+    pub max_depth: usize,
+    /// The number of steps left, each evaluated expression or VM instruction taking one, or `None` for no limit.
+    pub fuel: Option<u64>,
+    /// The instant after which the evaluation stops, or `None` for no limit.
+    pub deadline: Option<Instant>,
+    steps: u64,
+    pub quotas: Quotas,
+    /// The total length of the Strings on the stack, which may exceed it if the stack was changed directly.
+    string_bytes: usize,
+    pub engine: Engine,
+    /// The bytecode of the bodies called so far, see `Engine::Bytecode`.
+    pub(crate) program: Program,
 }
 impl Evaluator {
     pub fn new(input: Vec<Expr>) -> Self {
@@ -40,48 +115,197 @@ impl Evaluator {
             input,
             vars: vec![],
             stack: Vec::with_capacity(256),
-            builtins: vec![Self::add, Self::sub, Self::mul, Self::div, Self::r#mod, Self::dup, Self::app, Self::cat, Self::pop_stack, Self::swap, Self::print_stack, Self::eq, Self::not, Self::gt, Self::lt, Self::r#if],
+            builtins: Builtin::natives(),
+            frames: vec![],
+            max_depth: DEFAULT_MAX_DEPTH,
+            fuel: None,
+            deadline: None,
+            steps: 0,
+            quotas: Quotas::default(),
+            string_bytes: 0,
+            engine: Engine::default(),
+            program: Program::new(),
         }
     }
+    /// Registers a host builtin, callable as `ExprT::Builtin` with the index following the native ones.
+    pub fn register(&mut self, name: impl ToString, arity: usize, doc: impl ToString, func: HostFn) {
+        self.builtins.push(Builtin::host(name, arity, doc, func));
+    }
+    pub fn builtins(&self) -> &[Builtin] {
+        &self.builtins
+    }
     pub fn update(&mut self, expressions: Vec<Expr>) {
         self.input = expressions;
     }
+    /// Pushes `val`, if it fits in the quotas.
+    ///
+    /// A quota error has no position, the evaluator giving it the one of the current expression.
     pub fn push(&mut self, val: Value) -> Result<()> {
+        if let Some(max) = self.quotas.stack_depth.filter(|max| self.stack.len() >= *max) {
+            return error!(0, 0, ErrorKind::QuotaExceeded { quota: Quota::StackDepth, limit: max });
+        }
+        // The size of Quotes is checked once, when their literal is parsed, see `oversized`.
+        if let Value::String(s) = &val {
+            if let Some(max) = self.quotas.string_bytes {
+                if self.string_bytes + s.len() > max {
+                    self.recount();
+                }
+                if self.string_bytes + s.len() > max {
+                    return error!(0, 0, ErrorKind::QuotaExceeded { quota: Quota::StringBytes, limit: max });
+                }
+            }
+            self.string_bytes += s.len();
+        }
         self.stack.push(val);
         Ok(())
     }
     pub fn pop(&mut self, line: usize, column: usize) -> Result<Value> {
         match self.stack.pop() {
-            Some(v) => Ok(v),
-            None => error!(line, column, "Stack_underflow")
+            Some(v) => {
+                if let Value::String(s) = &v {
+                    self.string_bytes = self.string_bytes.saturating_sub(s.len());
+                }
+                Ok(v)
+            }
+            None => error!(line, column, ErrorKind::StackUnderflow)
         }
     }
+    /// Counts the String bytes again, after the stack was changed directly.
+    pub(crate) fn recount(&mut self) {
+        self.string_bytes = self.stack.iter().map(|val| match val {
+            Value::String(s) => s.len(),
+            _ => 0,
+        }).sum();
+    }
+    /// Evaluates `expr`, and the bodies it calls, to completion.
     pub fn eval_expr(&mut self, expr: Expr) -> Result<()> {
+        match (self.engine, &expr.r#type) {
+            (Engine::Bytecode, ExprT::Var(idx)) => match self.vars.get(*idx as usize).cloned().flatten() {
+                Some(body) => {
+                    self.tick(expr.line, expr.column)?;
+                    self.run_compiled(&body)
+                }
+                None => Err(unevaluated(*idx, expr.line, expr.column)),
+            }
+            _ => self.run(Rc::from([expr])),
+        }
+    }
+    /// Evaluates `body` in a new frame, leaving the frames as they were, even on error.
+    fn run(&mut self, body: Rc<[Expr]>) -> Result<()> {
+        let base = self.frames.len();
+        self.frames.push(Frame { body, pc: 0 });
+        let res = self.run_until(base);
+        self.frames.truncate(base);
+        res
+    }
+    pub(crate) fn run_until(&mut self, base: usize) -> Result<()> {
+        while self.frames.len() > base {
+            let frame = match self.frames.last_mut() {
+                Some(frame) => frame,
+                None => break,
+            };
+            match frame.body.get(frame.pc) {
+                Some(expr) => {
+                    let expr = expr.clone();
+                    frame.pc += 1;
+                    self.step(expr)?;
+                }
+                None => {
+                    self.frames.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Schedules `body` to be evaluated once the current expression returns.
+    ///
+    /// When the current expression is the last one of its body, the call is a tail call and reuses its frame, so that it does not count towards `max_depth`.
+    pub fn call(&mut self, body: Rc<[Expr]>, line: usize, column: usize) -> Result<()> {
+        if self.frames.last().is_some_and(|frame| frame.pc >= frame.body.len()) {
+            self.frames.pop();
+        }
+        if self.frames.len() >= self.max_depth {
+            return Err(too_deep(self.max_depth, line, column));
+        }
+        self.frames.push(Frame { body, pc: 0 });
+        Ok(())
+    }
+    /// Evaluates one expression, after checking the fuel and the deadline, so that the evaluation stops between two expressions.
+    fn step(&mut self, expr: Expr) -> Result<()> {
+        let (line, column) = (expr.line, expr.column);
+        self.step_unlocated(expr).map_err(|e| if e.line == 0 {
+            Error { line, column, ..e }
+        } else {
+            e
+        })
+    }
+    /// Takes one step of fuel, and checks the deadline from time to time.
+    pub(crate) fn tick(&mut self, line: usize, column: usize) -> Result<()> {
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return error!(line, column, ErrorKind::OutOfFuel);
+            }
+            *fuel -= 1;
+        }
+        self.steps = self.steps.wrapping_add(1);
+        if self.steps % DEADLINE_PERIOD == 0 && self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return error!(line, column, ErrorKind::DeadlineExceeded);
+        }
+        Ok(())
+    }
+    fn step_unlocated(&mut self, expr: Expr) -> Result<()> {
+        self.tick(expr.line, expr.column)?;
         match expr.r#type {
             ExprT::Integer(i) => self.push(Value::Integer(i)),
             ExprT::Real(r) => self.push(Value::Real(r)),
             ExprT::String(s) => self.push(Value::String(s)),
             ExprT::Quote(content) => self.push(Value::Quote(content)),
             ExprT::Symbol(sym) => self.push(Value::Symbol(sym)),
-            ExprT::Builtin(idx) => self.builtins[idx as usize](self, expr.line, expr.column),
+            ExprT::Builtin(idx) => self.call_builtin(idx as usize, expr.line, expr.column),
             ExprT::Store(idx, content) => {
                 let idx = idx as usize;
-                if idx < self.vars.len() {
-                    self.vars[idx] = content;
-                } else {
-                    self.vars.push(content);
+                if idx >= self.vars.len() {
+                    self.vars.resize(idx + 1, None);
                 }
+                self.vars[idx] = Some(content);
                 Ok(())
             }
             ExprT::Var(idx) => {
-                self.vars.clone().into_iter().nth(idx as usize).unwrap().iter().map(|expr| {
-                    self.eval_expr(expr.clone())
-                }).collect::<Result<()>>()
+                match self.vars.get(idx as usize).cloned().flatten() {
+                    Some(content) => self.call(content, expr.line, expr.column),
+                    None => Err(unevaluated(idx, expr.line, expr.column)),
+                }
             }
         }
     }
     pub fn eval(&mut self) -> Result<Option<Value>> {
-        self.input.clone().into_iter().map(|expr| self.eval_expr(expr)).collect::<Result<()>>()?;
-        Ok(self.stack.last().and_then(|v| Some(v.clone())))
+        let input: Rc<[Expr]> = self.input.clone().into();
+        match self.engine {
+            Engine::TreeWalker => self.run(input)?,
+            Engine::Bytecode => self.run_compiled(&input)?,
+        }
+        Ok(self.stack.last().cloned())
+    }
+    /// Compiles `body` and runs it on the VM, then forgets the blocks compiled for the run, as the next inputs will be new bodies.
+    ///
+    /// The definitions it made are compiled again when a later run calls them.
+    fn run_compiled(&mut self, body: &Rc<[Expr]>) -> Result<()> {
+        let mark = self.program.mark();
+        let (_, block) = self.program.block_of(body);
+        let res = self.run_block(block);
+        self.program.truncate(mark);
+        res
     }
 }
+
+/// The error of a call to the definition `idx` before its `let` is evaluated.
+pub(crate) fn unevaluated(idx: u16, line: usize, column: usize) -> Error {
+    Error::new(ErrorKind::UnevaluatedVariable(idx), line, column)
+        .with_note("help: a definition can only be used once its `let` has been evaluated.")
+}
+
+/// The error of a call nested more than `max_depth` deep.
+pub(crate) fn too_deep(max_depth: usize, line: usize, column: usize) -> Error {
+    Error::new(ErrorKind::CallDepthExceeded(max_depth), line, column)
+        .with_note("help: a call that is the last word of a definition or of an `if` branch does not count towards the limit.")
+}
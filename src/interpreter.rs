@@ -0,0 +1,459 @@
+use std::{rc::Rc, time::{Duration, Instant}};
+use crate::{diagnostic, lexer::escape, parser::{Expr, ExprT, Parser}, eval::{oversized, Engine, Evaluator, Quotas, Value}, builtins::Builtin, bytecode::{Compiled, Program}, checker, optimizer, Result, Error, ErrorKind, error};
+
+/// The first line of a session file, see `Interpreter::save_session`.
+pub const SESSION_HEADER: &str = "; kotek session 1";
+
+/// An expression evaluating to `val` in an empty session.
+fn literal(parser: &Parser, val: &Value) -> String {
+    match val {
+        Value::Integer(z) => format!("{}", z),
+        Value::Real(r) if r.is_nan() => "0.0 0.0 /".to_string(),
+        Value::Real(r) if r.is_infinite() => format!("{}1.0 0.0 /", if *r < 0. { "-" } else { "" }),
+        Value::Real(r) => format!("{:?}", r),
+        Value::String(s) => format!("\"{}\"", escape(s)),
+        Value::Symbol(sym) => format!("#{}", sym),
+        Value::Quote(content) => format!("[{}]", parser.decompile(content)),
+    }
+}
+
+/// A saved session state, see `Interpreter::checkpoint`.
+pub struct Checkpoint {
+    stack: Vec<Value>,
+    vars: Vec<Option<Rc<[Expr]>>>,
+    symbols: Vec<String>,
+}
+
+/// A parsed source, kept to show the lines errors point to.
+struct Source {
+    name: String,
+    first_line: usize,
+    text: String,
+}
+impl Source {
+    /// The number of the line following the source.
+    fn end(&self) -> usize {
+        self.first_line + self.text.matches('\n').count() + 1
+    }
+}
+
+/// The number of sources below which they are all kept, see `Interpreter::prune`.
+const SOURCES_KEPT: usize = 64;
+
+/// A Kotek session: the symbol table and the evaluator state.
+///
+/// Definitions made by one call to `eval_str` are visible to the next ones. The lines of the successive sources are numbered one after another, so that an error raised in an earlier definition can still be traced back to its source.
+pub struct Interpreter {
+    evaluator: Evaluator,
+    symbols: Vec<String>,
+    sources: Vec<Source>,
+    /// The number of sources left by the last `prune`.
+    pruned: usize,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    optimize: bool,
+    check: bool,
+}
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Interpreter {
+    pub fn new() -> Self {
+        Self {
+            evaluator: Evaluator::new(vec![]),
+            symbols: vec![],
+            sources: vec![],
+            pruned: 0,
+            fuel: None,
+            timeout: None,
+            optimize: true,
+            check: true,
+        }
+    }
+    /// Parses and evaluates `src`, returning the top of the stack.
+    pub fn eval_str(&mut self, src: impl ToString) -> Result<Option<Value>> {
+        self.eval_named("<input>", src)
+    }
+    /// Parses, checks and evaluates `src`, naming it `name` in diagnostics.
+    pub fn eval_named(&mut self, name: impl ToString, src: impl ToString) -> Result<Option<Value>> {
+        let expressions = self.parse_named(name, src).map_err(|mut errors| errors.remove(0))?;
+        if let Some(e) = self.check(&expressions).into_iter().next() {
+            return Err(e);
+        }
+        self.eval(expressions)
+    }
+    /// Infers the stack effects of `exprs`, and returns the errors their evaluation on the current stack would definitely raise.
+    ///
+    /// `eval` does not check the expressions it is given. Nothing is reported if checking is disabled with `set_check`.
+    pub fn check(&self, exprs: &[Expr]) -> Vec<Error> {
+        if !self.check {
+            return vec![];
+        }
+        checker::check(exprs, Some(&self.evaluator.stack), &self.evaluator.vars, self.builtins(), &self.symbols)
+    }
+    /// The inferred stack effect of the definition named `name`, as `( Integer -- Integer )`.
+    pub fn effect(&self, name: &str) -> Option<String> {
+        let idx = self.symbols.iter().position(|sym| sym == name)?;
+        checker::effect(idx as u16, &self.evaluator.vars, self.builtins(), &self.symbols)
+    }
+    /// Parses `src` against the session symbol table, registering its definitions, and returns the first error found.
+    pub fn parse(&mut self, src: impl ToString) -> Result<Vec<Expr>> {
+        self.parse_named("<input>", src).map_err(|mut errors| errors.remove(0))
+    }
+    /// Parses `src` as `parse` does, naming it `name` in diagnostics, and returns every error found.
+    ///
+    /// The definitions are only registered if there is no error, a Quote literal larger than `Quotas::quote_size` being one. The expressions are optimized, unless disabled with `set_optimize`.
+    pub fn parse_named(&mut self, name: impl ToString, src: impl ToString) -> std::result::Result<Vec<Expr>, Vec<Error>> {
+        let text = src.to_string();
+        let first_line = self.sources.last().map_or(1, Source::end);
+        let mut parser = self.parser(&text).starting_at(first_line);
+        self.prune();
+        self.sources.push(Source {
+            name: name.to_string(),
+            first_line,
+            text,
+        });
+        let (expressions, symbols) = parser.parse()?;
+        self.quote_sizes(&expressions)?;
+        self.symbols = symbols;
+        Ok(if self.optimize { optimizer::optimize(expressions, self.evaluator.quotas) } else { expressions })
+    }
+    /// Rejects the Quote literals of `exprs` larger than `Quotas::quote_size`, so that pushing them needs no check.
+    fn quote_sizes(&self, exprs: &[Expr]) -> std::result::Result<(), Vec<Error>> {
+        let mut errors = vec![];
+        if let Some(max) = self.evaluator.quotas.quote_size {
+            oversized(exprs, max, &mut errors);
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+    /// Drops the sources that no definition nor Quote on the stack comes from, but the last one, once their number doubled since the last time.
+    ///
+    /// An error pointing to a dropped source is rendered without its line.
+    fn prune(&mut self) {
+        if self.sources.len() < SOURCES_KEPT.max(2 * self.pruned) {
+            return;
+        }
+        let quotes = self.evaluator.stack.iter().filter_map(|val| match val {
+            Value::Quote(body) => Some(body),
+            _ => None,
+        });
+        let mut lines = self.evaluator.vars.iter().flatten().chain(quotes).filter_map(|body| Some(body.first()?.line)).collect::<Vec<_>>();
+        lines.sort_unstable();
+        let latest = self.sources.len() - 1;
+        let mut idx = 0;
+        self.sources.retain(|source| {
+            idx += 1;
+            let from = lines.partition_point(|line| *line < source.first_line);
+            idx > latest || lines.get(from).is_some_and(|line| *line < source.end())
+        });
+        self.pruned = self.sources.len();
+    }
+    /// Renders `e` as a diagnostic, with the source line it points to, coloured for a terminal if `colour` is set.
+    pub fn render(&self, e: &Error, colour: bool) -> String {
+        let source = self.sources.iter().rev()
+            .find(|source| (source.first_line..source.end()).contains(&e.line))
+            .map(|source| (source.name.as_str(), source.first_line, source.text.as_str()));
+        diagnostic::render(e, source, colour)
+    }
+    /// Evaluates already parsed expressions, returning the top of the stack.
+    ///
+    /// On error, the stack is left as it was after the last evaluated expression.
+    pub fn eval(&mut self, expressions: Vec<Expr>) -> Result<Option<Value>> {
+        self.evaluator.update(expressions);
+        self.arm();
+        self.evaluator.eval().map_err(|e| self.explain(e))
+    }
+    /// Gives the evaluator its fuel and deadline for one evaluation.
+    fn arm(&mut self) {
+        self.evaluator.fuel = self.fuel;
+        self.evaluator.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+    /// Adds the notes that need the session to an evaluation error.
+    fn explain(&self, e: Error) -> Error {
+        let note = match e.kind {
+            ErrorKind::UnevaluatedVariable(idx) => format!("${} is `{}`.", idx, self.symbols[idx as usize]),
+            ErrorKind::OutOfFuel => format!("the evaluation is limited to {} steps.", self.fuel.unwrap_or_default()),
+            ErrorKind::DeadlineExceeded => format!("the evaluation is limited to {:?}.", self.timeout.unwrap_or_default()),
+            _ => return e,
+        };
+        e.with_note(note)
+    }
+    /// Parses `src` as `parse_named` does, checks it as `check` does, on a stack of unknown contents, and compiles it to the contents of a `.ktc` file, to be run with `eval_compiled`.
+    pub fn compile(&mut self, name: impl ToString, src: impl ToString) -> std::result::Result<Vec<u8>, Vec<Error>> {
+        let expressions = self.parse_named(name, src)?;
+        if self.check {
+            let errors = checker::check(&expressions, None, &self.evaluator.vars, self.builtins(), &self.symbols);
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+        }
+        let source = self.sources.last().expect("parse_named registers the source");
+        let mut program = Program::new();
+        let (_, entry) = program.block_of(&expressions.into());
+        Ok(Compiled {
+            name: source.name.clone(),
+            source: source.text.clone(),
+            first_line: source.first_line,
+            symbols: self.symbols.clone(),
+            builtins: self.builtins().iter().map(|builtin| builtin.name.clone()).collect(),
+            program,
+            entry,
+        }.to_bytes())
+    }
+    /// Runs the contents of a `.ktc` file made by `compile`, returning the top of the stack.
+    ///
+    /// The file must have been compiled with the same builtins, in a session whose definitions extend the ones of this session, which a fresh interpreter always satisfies.
+    pub fn eval_compiled(&mut self, bytes: &[u8]) -> Result<Option<Value>> {
+        let compiled = Compiled::from_bytes(bytes)?;
+        let names = self.builtins().iter().map(|builtin| &builtin.name).take(compiled.builtins.len());
+        if !compiled.builtins.iter().eq(names) {
+            return error!(0, 0, ErrorKind::InvalidBytecode("it was compiled with other builtins".to_string()));
+        }
+        if !compiled.symbols.starts_with(&self.symbols) {
+            return error!(0, 0, ErrorKind::InvalidBytecode("it was compiled with other definitions".to_string()));
+        }
+        let entry = compiled.program.bodies.iter().find(|(_, block)| *block == compiled.entry);
+        let body = entry.map(|(constant, _)| compiled.program.constants[*constant as usize].clone());
+        self.prune();
+        self.sources.push(Source {
+            name: compiled.name,
+            first_line: compiled.first_line,
+            text: compiled.source,
+        });
+        if let Some(Value::Quote(body)) = body {
+            self.quote_sizes(&body).map_err(|mut errors| errors.remove(0))?;
+        }
+        self.symbols = compiled.symbols;
+        self.evaluator.program = compiled.program;
+        self.arm();
+        self.evaluator.run_block(compiled.entry).map_err(|e| self.explain(e))?;
+        Ok(self.evaluator.stack.last().cloned())
+    }
+    /// Calls the builtin or definition named `name`, returning the top of the stack.
+    pub fn call(&mut self, name: &str) -> Result<Option<Value>> {
+        match self.parser("").resolve(name) {
+            Some(r#type) => {
+                self.arm();
+                self.evaluator.eval_expr(Expr::new(r#type, 0, 0)).map_err(|e| self.explain(e))?;
+                Ok(self.evaluator.stack.last().cloned())
+            }
+            None => error!(0, 0, ErrorKind::UndefinedWord(name.to_string())),
+        }
+    }
+    /// Registers a host function as a builtin word.
+    ///
+    /// The stack must hold at least `arity` values when the word is called. Errors returned by `func` are reported at the call site.
+    pub fn register<F>(&mut self, name: impl ToString, arity: usize, doc: impl ToString, func: F) -> Result<()>
+    where
+        F: FnMut(&mut Evaluator) -> Result<()> + 'static
+    {
+        let name = name.to_string();
+        if name == "let" || matches!(self.parser("").resolve(&name), Some(ExprT::Builtin(_))) {
+            return error!(0, 0, ErrorKind::DuplicateBuiltin(name));
+        }
+        self.evaluator.register(name, arity, doc, Box::new(func));
+        Ok(())
+    }
+    /// Sets the maximum number of nested calls, `eval::DEFAULT_MAX_DEPTH` by default.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.evaluator.max_depth = max_depth;
+    }
+    /// Enables or disables `optimizer::optimize` for the next sources, enabled by default.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+    /// Enables or disables `check`, enabled by default.
+    pub fn set_check(&mut self, check: bool) {
+        self.check = check;
+    }
+    /// Chooses how the expressions are run, the bytecode VM by default.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.evaluator.engine = engine;
+    }
+    /// Limits every evaluation to `fuel` steps, each evaluated expression or VM instruction taking one, or lifts the limit with `None`.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+    /// The steps left by the last evaluation, if the fuel is limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.evaluator.fuel
+    }
+    /// Stops every evaluation that takes longer than `timeout`, or lifts the limit with `None`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+    /// Limits the memory taken by the values, see `Quotas`.
+    pub fn set_quotas(&mut self, quotas: Quotas) {
+        self.evaluator.quotas = quotas;
+    }
+    /// Forgets the definitions and empties the stack, keeping the host builtins and the limits.
+    pub fn reset(&mut self) {
+        self.rollback(Checkpoint {
+            stack: vec![],
+            vars: vec![],
+            symbols: vec![],
+        });
+        self.sources.clear();
+        self.pruned = 0;
+        self.evaluator.program = Program::new();
+    }
+    /// A parser knowing the builtins and definitions of the session.
+    pub fn parser(&self, src: impl ToString) -> Parser {
+        let mut parser = Parser::new(src, self.symbols.clone());
+        for builtin in self.evaluator.builtins().iter().filter(|builtin| builtin.is_host()) {
+            parser.register_builtin(&builtin.name);
+        }
+        parser
+    }
+    /// The builtin registry, native builtins first.
+    pub fn builtins(&self) -> &[Builtin] {
+        self.evaluator.builtins()
+    }
+    pub fn stack(&self) -> &[Value] {
+        &self.evaluator.stack
+    }
+    pub fn push(&mut self, val: Value) -> Result<()> {
+        self.evaluator.push(val)
+    }
+    pub fn pop(&mut self) -> Option<Value> {
+        self.evaluator.pop(0, 0).ok()
+    }
+    pub fn clear(&mut self) {
+        self.evaluator.stack.clear();
+        self.evaluator.recount();
+    }
+    pub fn symbols(&self) -> &[String] {
+        &self.symbols
+    }
+    /// The body of the definition named `name`, if it has been evaluated.
+    pub fn definition(&self, name: &str) -> Option<&[Expr]> {
+        let idx = self.symbols.iter().position(|sym| sym == name)?;
+        self.evaluator.vars.get(idx)?.as_deref()
+    }
+    /// Renders expressions back to source, naming builtins and definitions.
+    pub fn decompile(&self, exprs: &[Expr]) -> String {
+        self.parser("").decompile(exprs)
+    }
+    /// Renders a value as `Value::get_lit` does, naming the words of quotes.
+    pub fn show(&self, val: &Value) -> String {
+        match val {
+            Value::Quote(content) => format!("[{}]", self.decompile(content)),
+            _ => val.clone().get_lit(true),
+        }
+    }
+    /// Saves the stack and the definitions, to be restored with `rollback`.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            stack: self.evaluator.stack.clone(),
+            vars: self.evaluator.vars.clone(),
+            symbols: self.symbols.clone(),
+        }
+    }
+    /// Serializes the definitions and the stack to a session file.
+    ///
+    /// A session file is Kotek source meant to be evaluated in an empty session: its first line is `SESSION_HEADER`, then every name is declared with an empty body, so that the definitions can refer to each other in any order, then the definitions are given their bodies, and a last line pushes the stack values from bottom to top. A name whose `let` was never evaluated is declared in a Quote that is popped, so that it stays without a body.
+    pub fn save_session(&self) -> String {
+        let parser = self.parser("");
+        let mut session = format!("{}\n", SESSION_HEADER);
+        for (name, body) in self.symbols.iter().zip(&self.evaluator.vars) {
+            match body {
+                Some(_) => session.push_str(&format!("let {} ( )\n", name)),
+                None => session.push_str(&format!("[let {} ( )] pop\n", name)),
+            }
+        }
+        for (name, body) in self.symbols.iter().zip(&self.evaluator.vars) {
+            let body = match body {
+                Some(body) => body,
+                None => continue,
+            };
+            session.push_str(&format!("let {} ( {} )\n", name, parser.decompile(body)));
+        }
+        let stack = self.evaluator.stack.iter().map(|val| literal(&parser, val)).collect::<Vec<_>>();
+        session.push_str(&stack.join(" "));
+        session.push('\n');
+        session
+    }
+    /// Replaces the definitions and the stack with the ones of a session file.
+    ///
+    /// The session is left untouched if `src` fails to evaluate.
+    pub fn restore_session(&mut self, src: &str) -> Result<()> {
+        if src.lines().next() != Some(SESSION_HEADER) {
+            return error!(0, 0, ErrorKind::InvalidSession);
+        }
+        let checkpoint = self.checkpoint();
+        self.rollback(Checkpoint {
+            stack: vec![],
+            vars: vec![],
+            symbols: vec![],
+        });
+        match self.eval_named("<session>", src) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.rollback(checkpoint);
+                Err(e)
+            }
+        }
+    }
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.evaluator.stack = checkpoint.stack;
+        self.evaluator.recount();
+        self.evaluator.vars = checkpoint.vars;
+        self.symbols = checkpoint.symbols;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interpreter;
+    use crate::eval::{Quotas, Value};
+
+    /// A new interpreter with the session saved by `saved`.
+    fn restored(saved: &Interpreter) -> Interpreter {
+        let mut interpreter = Interpreter::new();
+        interpreter.restore_session(&saved.save_session()).expect("the session should be restored");
+        interpreter
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("let f ( g [1 [\"a\\\"b\\n\" [-1.5 2.5e10]]] ) let g ( -3 ) [let h ( 1 )] pop f 1.0e-7 -0.0 \"\\t\\\\\"").unwrap();
+        let mut restored = restored(&interpreter);
+        assert_eq!(restored.save_session(), interpreter.save_session());
+        let show = |interpreter: &Interpreter| interpreter.stack().iter().map(|val| interpreter.show(val)).collect::<Vec<_>>();
+        assert_eq!(show(&restored), show(&interpreter));
+        restored.call("f").unwrap();
+        assert_eq!(show(&restored)[5..], show(&interpreter)[..2]);
+        assert!(restored.definition("h").is_none());
+        assert!(restored.call("h").is_err());
+    }
+
+    #[test]
+    fn non_finite_reals() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("let f ( 1.0 0.0 / ) let g ( 0.0 0.0 / ) 1.0 0.0 /").unwrap();
+        let mut restored = restored(&interpreter);
+        restored.call("f").unwrap();
+        restored.call("g").unwrap();
+        assert!(matches!(restored.stack(), [Value::Real(a), Value::Real(b), Value::Real(c)] if a.is_infinite() && b.is_infinite() && c.is_nan()));
+    }
+
+    #[test]
+    fn compile_checks() {
+        assert!(Interpreter::new().compile("<test>", "\"x\" 1 +").is_err());
+        // The arguments of the script are not known yet.
+        assert!(Interpreter::new().compile("<test>", "\"x\" cat").is_ok());
+    }
+
+    #[test]
+    fn quote_sizes() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_quotas(Quotas { quote_size: Some(3), ..Quotas::default() });
+        let errors = interpreter.parse_named("<test>", "[1 [2 3 4]] [1 2 3] let f ( [[1 2]] [1 2 3 4] )").unwrap_err();
+        assert_eq!(errors.iter().map(|e| (e.line, e.column)).collect::<Vec<_>>(), [(1, 1), (1, 37)]);
+        assert!(interpreter.symbols().is_empty());
+        assert!(interpreter.eval_str("[1 2 3] dup dup").is_ok());
+    }
+}
@@ -0,0 +1,471 @@
+use crate::{Result, Error, ErrorKind, error};
+use std::{convert::TryFrom, iter::Peekable, str::CharIndices};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenT {
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Symbol(String),
+    String(String),
+    Integer(i32),
+    Real(f32),
+    Word(String),
+}
+/// A range of bytes of the source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub r#type: TokenT,
+    pub span: Span,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Checks whether `input` closes every `(`, `[` and `"` it opens.
+///
+/// As for the lexer, a `"` or a `;` inside a word, as in `a"b`, starts neither a string nor a comment. Superfluous closing brackets are left to the parser to report.
+pub fn is_complete(input: &str) -> bool {
+    let mut depth = 0usize;
+    let mut idx = 0;
+    let mut in_word = false;
+    while let Some(c) = input[idx..].chars().next() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            _ if in_word => {}
+            ';' => match input[idx..].find('\n') {
+                Some(end) => {
+                    idx += end;
+                    continue;
+                }
+                None => break,
+            }
+            '"' => match string_end(input, idx) {
+                Some(end) => {
+                    idx = end;
+                    continue;
+                }
+                None => return false,
+            }
+            _ => {}
+        }
+        in_word = !is_finishing(c);
+        idx += c.len_utf8();
+    }
+    depth == 0
+}
+
+/// Finds the end of the string literal starting at byte `start`, returning the byte index following its closing quote.
+pub fn string_end(input: &str, start: usize) -> Option<usize> {
+    let rest = &input[start..];
+    if let Some(raw) = rest.strip_prefix("\"\"\"") {
+        return raw.find("\"\"\"").map(|end| start + end + 6);
+    }
+    let mut chars = rest.char_indices().skip(1);
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => return Some(start + idx + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Escapes a string so that it can be read back as a string literal.
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reads a number literal: an optionally negative decimal Integer or Real (with an optional exponent), or an Integer prefixed by `0x`, `0o` or `0b`. Digits can be separated by underscores.
+fn number_literal(raw: &str) -> std::result::Result<TokenT, ErrorKind> {
+    let (negative, unsigned) = match raw.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, raw),
+    };
+    let digits = unsigned.replace('_', "");
+    let radix = match digits.get(..2) {
+        Some("0x") | Some("0X") => 16,
+        Some("0o") | Some("0O") => 8,
+        Some("0b") | Some("0B") => 2,
+        _ => 10,
+    };
+
+    if radix == 10 && digits.contains(['.', 'e', 'E']) {
+        let (mantissa, exponent) = match digits.find(['e', 'E']) {
+            Some(idx) => (&digits[..idx], Some(&digits[idx + 1..])),
+            None => (&digits[..], None),
+        };
+        let mut parts = mantissa.splitn(2, '.');
+        let integral = parts.next().unwrap_or("");
+        let fractional = parts.next();
+        let all_digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        if !all_digits(integral) || fractional.is_some_and(|f| !all_digits(f)) {
+            return Err(ErrorKind::InvalidReal { literal: raw.to_string(), expected: "digits on both sides of the decimal point" });
+        }
+        if exponent.is_some_and(|e| !all_digits(e.strip_prefix(['+', '-']).unwrap_or(e))) {
+            return Err(ErrorKind::InvalidReal { literal: raw.to_string(), expected: "digits in the exponent" });
+        }
+        return match digits.parse::<f32>() {
+            Ok(r) if r.is_finite() => Ok(TokenT::Real(if negative { -r } else { r })),
+            _ => Err(ErrorKind::OutOfRange { literal: raw.to_string(), r#type: "Real" }),
+        };
+    }
+
+    let body = if radix == 10 { &digits[..] } else { &digits[2..] };
+    if body.is_empty() || !body.chars().all(|c| c.is_digit(radix)) {
+        return Err(ErrorKind::InvalidNumber(raw.to_string()));
+    }
+    match i64::from_str_radix(body, radix).ok().map(|z| if negative { -z } else { z }).and_then(|z| i32::try_from(z).ok()) {
+        Some(z) => Ok(TokenT::Integer(z)),
+        None => Err(ErrorKind::OutOfRange { literal: raw.to_string(), r#type: "Integer" }),
+    }
+}
+
+/// Whether `c` ends a word, a number or a symbol.
+pub fn is_finishing(c: char) -> bool {
+    c.is_whitespace() || "()[]".contains(c)
+}
+
+/// Splits the source into tokens, skipping whitespaces and comments.
+pub struct Lexer<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    line: usize,
+    column: usize,
+    errors: Vec<Error>,
+}
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            chars: input.char_indices().peekable(),
+            line: 1,
+            column: 0,
+            errors: vec![],
+        }
+    }
+    /// Numbers the lines from `line` instead of 1.
+    pub fn starting_at(mut self, line: usize) -> Self {
+        self.line = line;
+        self
+    }
+    fn pop(&mut self) -> Result<char> {
+        match self.chars.next() {
+            Some((_, c)) => {
+                if c == '\n' {
+                    self.line += 1;
+                    self.column = 0;
+                } else {
+                    self.column += 1;
+                }
+                Ok(c)
+            }
+            None => error!(self.line, self.column + 1, ErrorKind::UnexpectedEof),
+        }
+    }
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, c)| *c)
+    }
+    /// The byte offset of the next character.
+    fn offset(&mut self) -> usize {
+        let len = self.input.len();
+        self.chars.peek().map_or(len, |(idx, _)| *idx)
+    }
+    /// Pops `expected`, leaving any other character to be read again.
+    fn advance(&mut self, expected: char) -> Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => self.pop().map(|_| ()),
+            Some(c) => error!(self.line, self.column + 1, ErrorKind::Expected { expected: format!("'{}'", expected), found: c.to_string() }),
+            None => self.pop().map(|_| ()),
+        }
+    }
+    /// Pops characters up to the next finishing one.
+    fn rest_of_word(&mut self) {
+        while self.peek().is_some_and(|c| !is_finishing(c)) {
+            let _ = self.pop();
+        }
+    }
+    /// Splits the whole input, returning the tokens and the errors found.
+    ///
+    /// An erroneous number is skipped and an invalid escape sequence is dropped from its string, so that the following tokens are still read.
+    pub fn tokenize(mut self) -> (Vec<Token>, Vec<Error>) {
+        let mut tokens = vec![];
+        loop {
+            match self.next_token() {
+                Ok(Some(token)) => tokens.push(token),
+                Ok(None) => break,
+                Err(e) if e.kind == ErrorKind::UnexpectedEof => {
+                    self.errors.push(e);
+                    break;
+                }
+                Err(e) => self.errors.push(e),
+            }
+        }
+        (tokens, self.errors)
+    }
+    fn next_token(&mut self) -> Result<Option<Token>> {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.pop()?;
+                }
+                Some(';') => while self.peek().is_some_and(|c| c != '\n') {
+                    self.pop()?;
+                }
+                Some(_) => break,
+                None => return Ok(None),
+            }
+        }
+
+        let start = self.offset();
+        let c = self.pop()?;
+        let (line, column) = (self.line, self.column);
+        let r#type = match c {
+            '(' => TokenT::LParen,
+            ')' => TokenT::RParen,
+            '[' => TokenT::LBracket,
+            ']' => TokenT::RBracket,
+            '"' => TokenT::String(self.string()?),
+            '#' => {
+                self.rest_of_word();
+                TokenT::Symbol(self.input[start + 1..self.offset()].to_string())
+            }
+            _ => {
+                self.rest_of_word();
+                let raw = &self.input[start..self.offset()];
+                if c.is_ascii_digit() || (c == '-' && raw[1..].starts_with(|c: char| c.is_ascii_digit())) {
+                    match number_literal(raw) {
+                        Ok(r#type) => r#type,
+                        Err(kind) => return error!(line, column, kind),
+                    }
+                } else {
+                    TokenT::Word(raw.to_string())
+                }
+            }
+        };
+        let end = self.offset();
+        Ok(Some(Token {
+            r#type,
+            span: Span { start, end },
+            line,
+            column,
+        }))
+    }
+    /// Reads a string literal, the opening quote being already popped.
+    fn string(&mut self) -> Result<String> {
+        let mut content = String::new();
+        let raw_start = self.offset();
+        if self.input[raw_start..].starts_with("\"\"") {
+            self.pop()?;
+            self.pop()?;
+            if self.peek() == Some('\n') {
+                self.pop()?;
+            }
+            let start = self.offset();
+            match self.input[start..].find("\"\"\"") {
+                Some(len) => {
+                    content.push_str(&self.input[start..start + len]);
+                    while self.offset() < start + len + 3 {
+                        self.pop()?;
+                    }
+                }
+                None => {
+                    while self.peek().is_some() {
+                        self.pop()?;
+                    }
+                    return error!(self.line, self.column + 1, ErrorKind::UnexpectedEof);
+                }
+            }
+        } else {
+            loop {
+                match self.pop()? {
+                    '"' => break,
+                    '\\' => match self.escape_sequence() {
+                        Ok(c) => content.push(c),
+                        Err(e) if e.kind == ErrorKind::UnexpectedEof => return Err(e),
+                        Err(e) => self.errors.push(e),
+                    }
+                    c => content.push(c),
+                }
+            }
+        }
+        Ok(content)
+    }
+    fn escape_sequence(&mut self) -> Result<char> {
+        let (line, column) = (self.line, self.column);
+        match self.pop()? {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '0' => Ok('\0'),
+            '\\' => Ok('\\'),
+            '"' => Ok('"'),
+            'u' => {
+                self.advance('{')?;
+                let mut code = String::new();
+                while self.peek().is_some_and(|c| c != '}' && c != '"') {
+                    code.push(self.pop()?);
+                }
+                self.advance('}')?;
+                match u32::from_str_radix(&code, 16).ok().filter(|_| code.len() <= 6).and_then(char::from_u32) {
+                    Some(c) => Ok(c),
+                    None => error!(line, column, ErrorKind::InvalidUnicodeEscape(code)),
+                }
+            }
+            c => Err(Error::new(ErrorKind::InvalidEscape(c), line, column)
+                .with_note("help: the escape sequences are \\n, \\t, \\r, \\0, \\\\, \\\" and \\u{...}.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tokens of `src`, or the kinds of the errors found.
+    fn lex(src: &str) -> std::result::Result<Vec<TokenT>, Vec<ErrorKind>> {
+        let (tokens, errors) = Lexer::new(src).tokenize();
+        if errors.is_empty() {
+            Ok(tokens.into_iter().map(|token| token.r#type).collect())
+        } else {
+            Err(errors.into_iter().map(|e| e.kind).collect())
+        }
+    }
+    fn out_of_range(literal: &str, r#type: &'static str) -> std::result::Result<Vec<TokenT>, Vec<ErrorKind>> {
+        Err(vec![ErrorKind::OutOfRange { literal: literal.to_string(), r#type }])
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(lex("42 -5 0xff 0XFF 0o17 0b101 1_000 -0x10"), Ok(vec![
+            TokenT::Integer(42),
+            TokenT::Integer(-5),
+            TokenT::Integer(255),
+            TokenT::Integer(255),
+            TokenT::Integer(15),
+            TokenT::Integer(5),
+            TokenT::Integer(1000),
+            TokenT::Integer(-16),
+        ]));
+        assert_eq!(lex("- -x x-1"), Ok(vec![TokenT::Word("-".to_string()), TokenT::Word("-x".to_string()), TokenT::Word("x-1".to_string())]));
+    }
+
+    #[test]
+    fn integer_range() {
+        assert_eq!(lex("2147483647 -2147483648"), Ok(vec![TokenT::Integer(i32::MAX), TokenT::Integer(i32::MIN)]));
+        assert_eq!(lex("2147483648"), out_of_range("2147483648", "Integer"));
+        assert_eq!(lex("-2147483649"), out_of_range("-2147483649", "Integer"));
+        assert_eq!(lex("0x1_0000_0000"), out_of_range("0x1_0000_0000", "Integer"));
+        assert_eq!(lex("99999999999999999999"), out_of_range("99999999999999999999", "Integer"));
+    }
+
+    #[test]
+    fn invalid_integers() {
+        for literal in ["0x", "0x_", "0xfg", "0b2", "12ab", "0o8"] {
+            assert_eq!(lex(literal), Err(vec![ErrorKind::InvalidNumber(literal.to_string())]), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn reals() {
+        assert_eq!(lex("1.5 -0.25 6.02e23 1e3 2.5E-2 1_000.5"), Ok(vec![
+            TokenT::Real(1.5),
+            TokenT::Real(-0.25),
+            TokenT::Real(6.02e23),
+            TokenT::Real(1e3),
+            TokenT::Real(2.5e-2),
+            TokenT::Real(1000.5),
+        ]));
+        assert_eq!(lex("1e39"), out_of_range("1e39", "Real"));
+    }
+
+    #[test]
+    fn invalid_reals() {
+        for literal in ["1.", "1.e5", "1.x"] {
+            assert_eq!(lex(literal), Err(vec![ErrorKind::InvalidReal { literal: literal.to_string(), expected: "digits on both sides of the decimal point" }]), "{}", literal);
+        }
+        for literal in ["1e", "1e+", "2.5e1.5"] {
+            assert_eq!(lex(literal), Err(vec![ErrorKind::InvalidReal { literal: literal.to_string(), expected: "digits in the exponent" }]), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn erroneous_numbers_are_skipped() {
+        let (tokens, errors) = Lexer::new("1 1. 2").tokenize();
+        assert_eq!(tokens.into_iter().map(|token| token.r#type).collect::<Vec<_>>(), vec![TokenT::Integer(1), TokenT::Integer(2)]);
+        assert_eq!((errors[0].line, errors[0].column), (1, 3));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(lex(r#""a\nb\tc\rd\0e\\f\"g""#), Ok(vec![TokenT::String("a\nb\tc\rd\0e\\f\"g".to_string())]));
+        assert_eq!(lex(r#""\u{48}\u{e9}\u{1F600}""#), Ok(vec![TokenT::String("H\u{e9}\u{1F600}".to_string())]));
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        assert_eq!(lex(r#""\q""#), Err(vec![ErrorKind::InvalidEscape('q')]));
+        assert_eq!(lex(r#""\u{110000}""#), Err(vec![ErrorKind::InvalidUnicodeEscape("110000".to_string())]));
+        assert_eq!(lex(r#""\u{D800}""#), Err(vec![ErrorKind::InvalidUnicodeEscape("D800".to_string())]));
+        assert_eq!(lex(r#""\u{}""#), Err(vec![ErrorKind::InvalidUnicodeEscape(String::new())]));
+        assert_eq!(lex(r#""\u{0000041}""#), Err(vec![ErrorKind::InvalidUnicodeEscape("0000041".to_string())]));
+        assert_eq!(lex(r#""\u41""#), Err(vec![ErrorKind::Expected { expected: "'{'".to_string(), found: "4".to_string() }]));
+    }
+
+    #[test]
+    fn invalid_escapes_are_dropped() {
+        let (tokens, errors) = Lexer::new(r#""a\qb" 1"#).tokenize();
+        assert_eq!(tokens.into_iter().map(|token| token.r#type).collect::<Vec<_>>(), vec![TokenT::String("ab".to_string()), TokenT::Integer(1)]);
+        assert_eq!((errors[0].line, errors[0].column), (1, 3));
+    }
+
+    #[test]
+    fn raw_strings() {
+        assert_eq!(lex(r#""""a \n "b" \q""""#), Ok(vec![TokenT::String(r#"a \n "b" \q"#.to_string())]));
+        assert_eq!(lex("\"\"\"\nfirst\nsecond\n\"\"\" 1"), Ok(vec![TokenT::String("first\nsecond\n".to_string()), TokenT::Integer(1)]));
+        assert_eq!(lex(r#""""""""#), Ok(vec![TokenT::String(String::new())]));
+        assert_eq!(lex(r#""" 1"#), Ok(vec![TokenT::String(String::new()), TokenT::Integer(1)]));
+    }
+
+    #[test]
+    fn unterminated_strings() {
+        assert_eq!(lex(r#""abc"#), Err(vec![ErrorKind::UnexpectedEof]));
+        assert_eq!(lex(r#""""abc""#), Err(vec![ErrorKind::UnexpectedEof]));
+        assert_eq!(string_end(r#""""a"b""" x"#, 0), Some(9));
+        assert_eq!(string_end(r#""a\"b" x"#, 0), Some(6));
+        assert_eq!(string_end(r#""a\""#, 0), None);
+    }
+
+    #[test]
+    fn complete_inputs() {
+        assert!(!is_complete(r#"[1 "a]" ( ; ]"#));
+        assert!(is_complete(r#"a"b [c]"#));
+        assert!(is_complete("a;b"));
+        assert!(is_complete(r#"[a"b] "c" ; "d"#));
+        assert!(!is_complete(r#""a"b "c"#));
+        assert!(!is_complete(r#"a "b"#));
+        assert!(!is_complete("a;b ["));
+    }
+}
@@ -0,0 +1,24 @@
+pub mod lexer;
+pub mod parser;
+pub mod eval;
+pub mod builtins;
+mod checker;
+pub mod bytecode;
+pub mod optimizer;
+mod diagnostic;
+mod error;
+mod interpreter;
+mod vm;
+
+pub use eval::Value;
+pub use error::{Error, ErrorKind, Quota};
+pub use interpreter::{Interpreter, Checkpoint, SESSION_HEADER};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[macro_export]
+macro_rules! error {
+    ($line:expr, $column:expr, $kind:expr) => {
+        Err($crate::Error::new($kind, $line, $column))
+    }
+}
@@ -1,68 +1,272 @@
-mod parser;
-mod eval;
-mod builtins;
-use parser::Parser;
-use eval::Evaluator;
-use rustyline::{error::ReadlineError, Editor};
-
-fn print_err(e: Error) {
-    eprintln!("\x1b[0;31m{}:{} | {}\x1b[0m", e.0, e.1, e.2);
+mod repl;
+use kotek::{Interpreter, Value, Error, ErrorKind, builtins, bytecode::MAGIC, lexer::escape, eval::{Engine, Quotas}};
+use std::{env, fmt::Display, fs, io::{self, IsTerminal, Read}, path::{Path, PathBuf}, process, str::FromStr, time::Duration};
+
+/// Whether stderr is a terminal, and can thus be coloured.
+fn colour() -> bool {
+    io::stderr().is_terminal()
 }
 
-fn repl() {
-    let mut evaluator = Evaluator::new(vec![]);
-    let mut symbols = vec![];
-    let mut reader = Editor::<()>::new();
-    loop {
-        let line = reader.readline("kotek> ");
-        match line {
-            Ok(line) => {
-                reader.add_history_entry(line.as_str());
-                if line == "quit" {
-                    return;
-                }
+fn print_err(interpreter: &Interpreter, e: Error) {
+    eprint!("{}", interpreter.render(&e, colour()));
+}
 
-                let mut parser = Parser::new(line.as_str(), symbols.clone());
-                let (expressions, new_syms)= match parser.parse() {
-                    Ok(res) => res,
-                    Err(e) => {
-                        print_err(e);
-                        continue;
-                    }
-                };
-                symbols = new_syms;
-                evaluator.update(expressions);
-                match evaluator.eval() {
-                    Ok(val) => match val {
-                        Some(top) => println!("=> {} :: {}", top.clone().get_lit(true), top.get_type()),
-                        None => {}
-                    }
-                    Err(e) => print_err(e),
-                } 
-            }
-            Err(ReadlineError::Interrupted) => {
-                println!("=> #Interrupt");
-            }
-            Err(ReadlineError::Eof) => return,
-            Err(_) => {
-                eprintln!("An error occured while reading input, please retry.");
-            }
+/// Prints a failure that is not a Kotek error, in red if stderr is a terminal.
+fn print_failure(msg: impl Display) {
+    if colour() {
+        eprintln!("\x1b[0;31m{}\x1b[0m", msg);
+    } else {
+        eprintln!("{}", msg);
+    }
+}
+
+fn strip_shebang(source: String) -> String {
+    if source.starts_with("#!") {
+        // Keep the newline so that error positions still match the file.
+        match source.find('\n') {
+            Some(idx) => source[idx..].to_string(),
+            None => String::new(),
         }
+    } else {
+        source
+    }
+}
+
+const EXIT_RUNTIME: i32 = 1;
+const EXIT_PARSE: i32 = 2;
+const EXIT_USAGE: i32 = 3;
+
+const USAGE: &str = "Usage: kotek [options] [<file> | -e <expr> | -] [args...]
+
+  <file>     Run a script, or a compiled .ktc file.
+  -e <expr>  Evaluate an expression and print the final stack.
+  -          Evaluate the standard input and print the final stack.
+  -t, --top  Only print the top of the stack.
+  --compile <file>
+             Compile a script to a .ktc file next to it, without running it.
+  --tree     Run with the tree-walking evaluator instead of the bytecode VM.
+  --no-optimize
+             Run the code as written, without folding constants nor inlining.
+  --no-check Do not infer the stack effects before evaluation.
+  --session <file>
+             Restore the REPL session from <file>, and save it there on exit.
+  --max-depth <n>
+             Limit the number of nested calls to <n> (100000 by default).
+  --fuel <n> Stop any evaluation after <n> steps.
+  --timeout <seconds>
+             Stop any evaluation that takes longer than <seconds>.
+  --max-stack <n>
+             Limit the stack to <n> values.
+  --max-string-bytes <n>
+             Limit the Strings on the stack to <n> bytes in total.
+  --max-quote <n>
+             Limit the Quotes to <n> expressions, nested ones included.
+  -h, --help Print this message.
+  --builtins Print the builtin table in Markdown.
+
+Without any argument, kotek starts the REPL.";
+
+fn run(mut interpreter: Interpreter, name: &str, source: String, args: Vec<String>) -> std::result::Result<Interpreter, i32> {
+    let expressions = interpreter.parse_named(name, source).map_err(|errors| {
+        errors.into_iter().for_each(|e| print_err(&interpreter, e));
+        EXIT_PARSE
+    })?;
+
+    for arg in args {
+        interpreter.push(Value::String(arg)).map_err(|e| {
+            print_err(&interpreter, e);
+            EXIT_RUNTIME
+        })?;
+    }
+    let errors = interpreter.check(&expressions);
+    if !errors.is_empty() {
+        errors.into_iter().for_each(|e| print_err(&interpreter, e));
+        return Err(EXIT_PARSE);
+    }
+    interpreter.eval(expressions).map_err(|e| {
+        print_err(&interpreter, e);
+        EXIT_RUNTIME
+    })?;
+    Ok(interpreter)
+}
+
+/// Runs a `.ktc` file, an invalid one being reported as unreadable input.
+fn run_compiled(mut interpreter: Interpreter, bytes: Vec<u8>, args: Vec<String>) -> std::result::Result<Interpreter, i32> {
+    for arg in args {
+        interpreter.push(Value::String(arg)).map_err(|e| {
+            print_err(&interpreter, e);
+            EXIT_RUNTIME
+        })?;
     }
+    interpreter.eval_compiled(&bytes).map_err(|e| {
+        let code = if matches!(e.kind, ErrorKind::InvalidBytecode(_)) { EXIT_USAGE } else { EXIT_RUNTIME };
+        print_err(&interpreter, e);
+        code
+    })?;
+    Ok(interpreter)
 }
 
-fn main() -> Result<()> {
-    repl();
-    Ok(())
+fn read_bytes(path: &str) -> std::result::Result<Vec<u8>, i32> {
+    let bytes = if path == "-" {
+        let mut bytes = vec![];
+        io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        fs::read(path)
+    };
+    bytes.map_err(|e| {
+        print_failure(format!("Failed to read {}: {}", path, e));
+        EXIT_USAGE
+    })
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
-#[derive(Debug)]
-pub struct Error(usize, usize, String);
+fn to_source(path: &str, bytes: Vec<u8>) -> std::result::Result<String, i32> {
+    String::from_utf8(bytes).map(strip_shebang).map_err(|_| {
+        print_failure(format!("Failed to read {}: stream did not contain valid UTF-8", path));
+        EXIT_USAGE
+    })
+}
+
+fn read_source(path: &str) -> std::result::Result<String, i32> {
+    read_bytes(path).and_then(|bytes| to_source(path, bytes))
+}
+
+/// Compiles the script at `path` to a `.ktc` file next to it.
+fn compile(mut interpreter: Interpreter, path: &str) -> std::result::Result<(), i32> {
+    let source = read_source(path)?;
+    let bytes = interpreter.compile(path, source).map_err(|errors| {
+        errors.into_iter().for_each(|e| print_err(&interpreter, e));
+        EXIT_PARSE
+    })?;
+    let output = Path::new(path).with_extension("ktc");
+    fs::write(&output, bytes).map_err(|e| {
+        print_failure(format!("Failed to write {}: {}", output.display(), e));
+        EXIT_USAGE
+    })
+}
+
+/// Prints the stack left by a script, one value per line, or only its top.
+fn print_plain(interpreter: Interpreter, top_only: bool) {
+    let stack = interpreter.stack();
+    let values = if top_only { &stack[stack.len().saturating_sub(1)..] } else { stack };
+    for val in values {
+        println!("{}", plain(&interpreter, val));
+    }
+}
+
+/// Renders a value without colours, Strings unquoted but escaped so that each value takes one line.
+fn plain(interpreter: &Interpreter, val: &Value) -> String {
+    match val {
+        Value::String(s) => escape(s),
+        Value::Quote(content) => format!("[{}]", interpreter.decompile(content)),
+        val => val.clone().get_lit(false),
+    }
+}
+
+fn usage_error() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+/// Parses the value of an option, exiting with the usage if it is missing or invalid.
+fn option_value<T: FromStr>(args: &mut impl Iterator<Item = String>) -> T {
+    match args.next().and_then(|value| value.parse().ok()) {
+        Some(value) => value,
+        None => usage_error(),
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+    let mut top_only = false;
+    let mut session = None;
+    let mut interpreter = Interpreter::new();
+    let mut quotas = Quotas::default();
+    while let Some(arg) = args.peek() {
+        match arg.as_str() {
+            "-t" | "--top" => top_only = true,
+            "--tree" => interpreter.set_engine(Engine::TreeWalker),
+            "--no-optimize" => interpreter.set_optimize(false),
+            "--no-check" => interpreter.set_check(false),
+            "--compile" => {
+                args.next();
+                let path = option_value::<String>(&mut args);
+                if let Err(code) = compile(interpreter, &path) {
+                    process::exit(code);
+                }
+                return;
+            }
+            "--session" => {
+                args.next();
+                session = Some(PathBuf::from(option_value::<String>(&mut args)));
+                continue;
+            }
+            "--max-depth" => {
+                args.next();
+                interpreter.set_max_depth(option_value(&mut args));
+                continue;
+            }
+            "--fuel" => {
+                args.next();
+                interpreter.set_fuel(Some(option_value(&mut args)));
+                continue;
+            }
+            "--max-stack" => {
+                args.next();
+                quotas.stack_depth = Some(option_value(&mut args));
+                continue;
+            }
+            "--max-string-bytes" => {
+                args.next();
+                quotas.string_bytes = Some(option_value(&mut args));
+                continue;
+            }
+            "--max-quote" => {
+                args.next();
+                quotas.quote_size = Some(option_value(&mut args));
+                continue;
+            }
+            "--timeout" => {
+                args.next();
+                match Duration::try_from_secs_f64(option_value(&mut args)) {
+                    Ok(timeout) => interpreter.set_timeout(Some(timeout)),
+                    Err(_) => usage_error(),
+                }
+                continue;
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            "--builtins" => {
+                print!("{}", builtins::markdown_table());
+                return;
+            }
+            _ => break,
+        }
+        args.next();
+    }
+    interpreter.set_quotas(quotas);
+
+    let res = match args.next() {
+        Some(flag) if flag == "-e" => match args.next() {
+            Some(expr) => run(interpreter, "<expr>", expr, args.collect()).map(|interpreter| print_plain(interpreter, top_only)),
+            None => usage_error(),
+        }
+        Some(path) if path == "-" => read_source(&path)
+            .and_then(|source| run(interpreter, "<stdin>", source, args.collect()))
+            .map(|interpreter| print_plain(interpreter, top_only)),
+        Some(path) => read_bytes(&path).and_then(|bytes| if bytes.starts_with(MAGIC) {
+            run_compiled(interpreter, bytes, args.collect())
+        } else {
+            to_source(&path, bytes).and_then(|source| run(interpreter, &path, source, args.collect()))
+        }).map(|_| ()),
+        None => {
+            repl::repl(interpreter, session);
+            Ok(())
+        }
+    };
 
-#[macro_export]
-macro_rules! error {
-    ($line:expr, $column:expr, $($arg:tt)*) => {
-        Err(Error($line, $column, format_args!($($arg)*).to_string()))
+    if let Err(code) = res {
+        process::exit(code);
     }
 }
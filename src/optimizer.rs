@@ -0,0 +1,337 @@
+//! An optimizing pass over parsed expressions, run by `Interpreter::parse_named` unless disabled.
+//!
+//! It only rewrites code into code with the same results. A rewritten word that would have failed may succeed, though: `swap swap` on an empty stack is removed, and an inlined definition no longer counts as a nested call.
+
+use std::{collections::{HashMap, HashSet}, rc::Rc};
+use crate::{builtins::Builtin, eval::{size, Evaluator, Quotas, Value}, parser::{Expr, ExprT}};
+
+/// The builtins without side effects, evaluated ahead of time when their arguments are literals.
+const PURE: &[&str] = &["+", "-", "*", "/", "%", "dup", "swap", "pop", "cat", "eq", "not", "gt", "lt"];
+
+/// The maximum size of an inlined body, counted as `Quotas::quote_size` is.
+const INLINE_SIZE: usize = 8;
+
+/// The maximum length in bytes of a String computed ahead of time, so that folding `dup` and `cat` cannot build huge literals.
+const FOLD_BYTES: usize = 256;
+
+/// Optimizes a parsed source.
+///
+/// - Pure builtins whose arguments are literals are replaced with their results: `3 4 *` becomes `12`. They are not if they fail, with `quotas` applied to their arguments and results, so that the error is still raised when the code runs, nor if a result is an infinite or NaN Real, which has no literal.
+/// - Short definitions, made once by a top-level `let` and not recursive, are inlined at the top-level calls following their `let`. Definition bodies and Quotes, which may be run after a later source redefined the words they call, are left as written.
+/// - `swap swap` and `dup pop` are removed.
+/// - An `if` whose condition and branches are literals, and an `app` of a literal Quote, are replaced with the code they run.
+pub fn optimize(exprs: Vec<Expr>, quotas: Quotas) -> Vec<Expr> {
+    let mut evaluator = Evaluator::new(vec![]);
+    evaluator.quotas = quotas;
+    let mut optimizer = Optimizer {
+        evaluator,
+        candidates: candidates(&exprs),
+        inlined: HashMap::new(),
+        nested: 0,
+    };
+    optimizer.body(&exprs)
+}
+
+/// Whether `expr` is the native builtin `name`.
+fn is(expr: &ExprT, name: &str) -> bool {
+    matches!(expr, ExprT::Builtin(idx) if Builtin::native_index(name) == Some(*idx))
+}
+
+/// The value pushed by a literal expression.
+fn value(expr: &ExprT) -> Option<Value> {
+    Some(match expr {
+        ExprT::Integer(z) => Value::Integer(*z),
+        ExprT::Real(r) => Value::Real(*r),
+        ExprT::String(s) => Value::String(s.clone()),
+        ExprT::Symbol(sym) => Value::Symbol(sym.clone()),
+        ExprT::Quote(body) => Value::Quote(body.clone()),
+        _ => return None,
+    })
+}
+
+/// The literal expression pushing `val`.
+fn literal(val: Value) -> ExprT {
+    match val {
+        Value::Integer(z) => ExprT::Integer(z),
+        Value::Real(r) => ExprT::Real(r),
+        Value::String(s) => ExprT::String(s),
+        Value::Symbol(sym) => ExprT::Symbol(sym),
+        Value::Quote(body) => ExprT::Quote(body),
+    }
+}
+
+/// Counts the `let` of each definition in `exprs`, and collects the definitions they call.
+fn scan(exprs: &[Expr], stores: &mut HashMap<u16, usize>, calls: &mut HashSet<u16>) {
+    for expr in exprs {
+        match &expr.r#type {
+            ExprT::Quote(body) => scan(body, stores, calls),
+            ExprT::Store(idx, body) => {
+                *stores.entry(*idx).or_default() += 1;
+                scan(body, stores, calls);
+            }
+            ExprT::Var(idx) => {
+                calls.insert(*idx);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The definitions that may be inlined: made once, by a top-level `let` whose body makes none, and not calling themselves, even through other definitions.
+fn candidates(exprs: &[Expr]) -> HashSet<u16> {
+    let mut stores = HashMap::new();
+    scan(exprs, &mut stores, &mut HashSet::new());
+    let mut graph = HashMap::new();
+    for expr in exprs {
+        if let ExprT::Store(idx, body) = &expr.r#type {
+            let (mut nested, mut calls) = (HashMap::new(), HashSet::new());
+            scan(body, &mut nested, &mut calls);
+            if stores[idx] == 1 && nested.is_empty() {
+                graph.insert(*idx, calls);
+            }
+        }
+    }
+    let recursive = recursive(&graph);
+    graph.into_keys().filter(|idx| !recursive.contains(idx)).collect()
+}
+
+/// The definitions of `graph` that call themselves, directly or through others: the ones of its strongly connected components that have a cycle, found in linear time with Tarjan's algorithm.
+fn recursive(graph: &HashMap<u16, HashSet<u16>>) -> HashSet<u16> {
+    // The visiting order and the lowest one reachable, of each visited definition.
+    let mut order: HashMap<u16, (usize, usize)> = HashMap::new();
+    let (mut stack, mut on_stack) = (vec![], HashSet::new());
+    let mut recursive = HashSet::new();
+    let callees = |def: u16| graph[&def].iter().copied().filter(|callee| graph.contains_key(callee)).collect::<Vec<_>>();
+    for &root in graph.keys() {
+        if order.contains_key(&root) {
+            continue;
+        }
+        // The definitions being visited, with their callees and how many of them were visited.
+        let mut work = vec![(root, callees(root), 0)];
+        let n = order.len();
+        order.insert(root, (n, n));
+        stack.push(root);
+        on_stack.insert(root);
+        while let Some((def, next, visited)) = work.last_mut() {
+            let def = *def;
+            if let Some(&callee) = next.get(*visited) {
+                *visited += 1;
+                match order.get(&callee) {
+                    None => {
+                        let n = order.len();
+                        order.insert(callee, (n, n));
+                        stack.push(callee);
+                        on_stack.insert(callee);
+                        work.push((callee, callees(callee), 0));
+                    }
+                    Some(&(callee_index, _)) if on_stack.contains(&callee) => {
+                        let entry = order.entry(def).or_default();
+                        entry.1 = entry.1.min(callee_index);
+                    }
+                    Some(_) => {}
+                }
+                continue;
+            }
+            work.pop();
+            let (def_index, def_low) = order[&def];
+            if let Some((caller, ..)) = work.last() {
+                let entry = order.entry(*caller).or_default();
+                entry.1 = entry.1.min(def_low);
+            }
+            if def_low == def_index {
+                let mut component = vec![];
+                while let Some(member) = stack.pop() {
+                    on_stack.remove(&member);
+                    component.push(member);
+                    if member == def {
+                        break;
+                    }
+                }
+                if component.len() > 1 || graph[&def].contains(&def) {
+                    recursive.extend(component);
+                }
+            }
+        }
+    }
+    recursive
+}
+
+/// The line and column of an expression.
+type Position = (usize, usize);
+
+struct Optimizer {
+    /// Evaluates the pure builtins.
+    evaluator: Evaluator,
+    candidates: HashSet<u16>,
+    /// The optimized bodies of the candidates whose `let` has been met, and the position of the `let`.
+    inlined: HashMap<u16, (Position, Rc<[Expr]>)>,
+    /// The number of Quotes and definition bodies around the expressions being optimized.
+    nested: usize,
+}
+impl Optimizer {
+    fn body(&mut self, exprs: &[Expr]) -> Vec<Expr> {
+        let mut out = vec![];
+        for expr in exprs {
+            self.expr(&mut out, expr);
+        }
+        out
+    }
+    fn expr(&mut self, out: &mut Vec<Expr>, expr: &Expr) {
+        let (line, column) = (expr.line, expr.column);
+        let r#type = match &expr.r#type {
+            ExprT::Quote(body) => ExprT::Quote(self.nested(body)),
+            ExprT::Store(idx, body) => {
+                let body = self.nested(body);
+                if self.candidates.contains(idx) && size(&body) <= INLINE_SIZE {
+                    self.inlined.insert(*idx, ((line, column), body.clone()));
+                }
+                ExprT::Store(*idx, body)
+            }
+            // A definition is only inlined after its `let`, as it may not have been evaluated before.
+            ExprT::Var(idx) => match self.inlined.get(idx).filter(|(at, _)| self.nested == 0 && *at < (line, column)) {
+                Some((_, body)) => return self.splice(out, &body.clone()),
+                None => ExprT::Var(*idx),
+            }
+            r#type => r#type.clone(),
+        };
+        out.push(Expr::new(r#type, line, column));
+        self.reduce(out);
+    }
+    /// Optimizes the body of a Quote or of a definition.
+    fn nested(&mut self, exprs: &[Expr]) -> Rc<[Expr]> {
+        self.nested += 1;
+        let body = self.body(exprs);
+        self.nested -= 1;
+        body.into()
+    }
+    /// Appends already optimized expressions.
+    fn splice(&mut self, out: &mut Vec<Expr>, exprs: &[Expr]) {
+        for expr in exprs {
+            out.push(expr.clone());
+            self.reduce(out);
+        }
+    }
+    /// Rewrites the end of `out`, after an expression was appended.
+    fn reduce(&mut self, out: &mut Vec<Expr>) {
+        let len = out.len();
+        let (previous, last) = match &out[..] {
+            [.., previous, last] => (Some(&previous.r#type), &last.r#type),
+            [last] => (None, &last.r#type),
+            [] => return,
+        };
+        let after = |name| previous.is_some_and(|previous| is(previous, name));
+        if (is(last, "swap") && after("swap")) || (is(last, "pop") && after("dup")) {
+            out.truncate(len - 2);
+        } else if is(last, "app") {
+            if let Some(ExprT::Quote(body)) = previous.cloned() {
+                out.truncate(len - 2);
+                self.splice(out, &body);
+            }
+        } else if is(last, "if") && len >= 4 {
+            if let (ExprT::Symbol(cond), ExprT::Quote(then), ExprT::Quote(r#else)) = (&out[len - 4].r#type, &out[len - 3].r#type, &out[len - 2].r#type) {
+                let branch = match cond.as_str() {
+                    "t" => then.clone(),
+                    "f" => r#else.clone(),
+                    _ => return,
+                };
+                out.truncate(len - 4);
+                self.splice(out, &branch);
+            }
+        } else if let ExprT::Builtin(idx) = *last {
+            self.fold(out, idx);
+        }
+    }
+    /// Evaluates the pure builtin `idx` ending `out`, if its arguments are literals and it succeeds with results that are short enough and can be written as literals.
+    fn fold(&mut self, out: &mut Vec<Expr>, idx: u16) {
+        let builtin = match self.evaluator.builtins.get(idx as usize) {
+            Some(builtin) if PURE.contains(&builtin.name.as_str()) && out.len() > builtin.arity() => builtin,
+            _ => return,
+        };
+        let start = out.len() - 1 - builtin.arity();
+        let args = match out[start..out.len() - 1].iter().map(|expr| value(&expr.r#type)).collect::<Option<Vec<_>>>() {
+            Some(args) => args,
+            None => return,
+        };
+        self.evaluator.stack.clear();
+        self.evaluator.recount();
+        if args.into_iter().try_for_each(|arg| self.evaluator.push(arg)).is_err() || self.evaluator.call_builtin(idx as usize, 0, 0).is_err() {
+            return;
+        }
+        // Infinities and NaN have no literal, and would be decompiled to words.
+        if self.evaluator.stack.iter().any(|val| matches!(val, Value::String(s) if s.len() > FOLD_BYTES) || matches!(val, Value::Real(r) if !r.is_finite())) {
+            return;
+        }
+        let (line, column) = (out[start].line, out[start].column);
+        out.truncate(start);
+        for val in self.evaluator.stack.drain(..).collect::<Vec<_>>() {
+            out.push(Expr::new(literal(val), line, column));
+            self.reduce(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{eval::Quotas, Interpreter};
+
+    /// The optimized source of `src`, with `quotas`, in a session defining `x` and `y`.
+    fn optimized_with(src: &str, quotas: Quotas) -> String {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("let x ( 1 ) let y ( 2 )").unwrap();
+        interpreter.set_quotas(quotas);
+        let exprs = interpreter.parse(src).expect("the source should parse");
+        interpreter.decompile(&exprs)
+    }
+
+    fn optimized(src: &str) -> String {
+        optimized_with(src, Quotas::default())
+    }
+
+    #[test]
+    fn folding() {
+        assert_eq!(optimized("3 4 * 2 +"), "14");
+        assert_eq!(optimized("\"a\" \"b\" cat dup"), "\"ab\" \"ab\"");
+        assert_eq!(optimized("1 2 lt not"), "#f");
+        assert_eq!(optimized("1 x +"), "1 x +");
+    }
+
+    #[test]
+    fn folding_refusals() {
+        assert_eq!(optimized("2147483647 1 +"), "2147483647 1 +");
+        assert_eq!(optimized("1 0 /"), "1 0 /");
+        assert_eq!(optimized("1.0 0.0 /"), "1.0 0.0 /");
+        assert_eq!(optimized("\"a\" 1 +"), "\"a\" 1 +");
+        let quotas = Quotas { string_bytes: Some(3), ..Quotas::default() };
+        assert_eq!(optimized_with("\"ab\" \"cd\" cat", quotas), "\"ab\" \"cd\" cat");
+        let long = format!("\"{}\"", "a".repeat(200));
+        assert_eq!(optimized(&format!("{} dup cat", long)), format!("{} {} cat", long, long));
+    }
+
+    #[test]
+    fn inlining() {
+        assert_eq!(optimized("let sq ( dup * ) 3 sq"), "let sq ( dup * ) 9");
+        // Not before the `let`, which may not have been evaluated.
+        assert_eq!(optimized("3 sq let sq ( dup * )"), "3 sq let sq ( dup * )");
+        // Not in a Quote or a body, which may run after `sq` is redefined.
+        assert_eq!(optimized("let sq ( dup * ) [sq] let q ( sq )"), "let sq ( dup * ) [sq] let q ( sq )");
+        assert_eq!(optimized("let a ( b ) let b ( 1 ) a"), "let a ( b ) let b ( 1 ) b");
+        // Not if recursive, directly or not.
+        assert_eq!(optimized("let l ( l ) l"), "let l ( l ) l");
+        assert_eq!(optimized("let f ( g ) let g ( f ) f"), "let f ( g ) let g ( f ) f");
+        assert_eq!(optimized("let r ( 1 ) let r ( 2 ) r"), "let r ( 1 ) let r ( 2 ) r");
+    }
+
+    #[test]
+    fn stack_shuffles() {
+        assert_eq!(optimized("x swap swap y"), "x y");
+        assert_eq!(optimized("x dup pop"), "x");
+        assert_eq!(optimized("swap dup swap"), "swap dup swap");
+    }
+
+    #[test]
+    fn literal_control() {
+        assert_eq!(optimized("#t [x] [y] if #f [x] [y] if"), "x y");
+        assert_eq!(optimized("#x [x] [y] if"), "#x [x] [y] if");
+        assert_eq!(optimized("[x 1 2 +] app"), "x 3");
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{Result, error, Error};
+use std::rc::Rc;
+use crate::{builtins::Builtin, lexer::{escape, Lexer, Token, TokenT}, Result, Error, ErrorKind, Quota, error};
+
+/// The maximum depth of nested brackets, so that the passes over the expressions, which recurse into them, cannot overflow the native stack.
+pub const MAX_NESTING: usize = 256;
 
 #[derive(Clone, Debug)]
 pub enum ExprT {
@@ -6,8 +10,8 @@ pub enum ExprT {
     String(String),
     Integer(i32),
     Real(f32),
-    Quote(Vec<Expr>),
-    Store(u16, Vec<Expr>),
+    Quote(Rc<[Expr]>),
+    Store(u16, Rc<[Expr]>),
     Var(u16),
     Builtin(u16)
 }
@@ -15,12 +19,12 @@ impl ExprT {
     pub fn get_lit(self) -> String {
         match self {
             Self::Symbol(sym) => sym,
-            Self::String(s) => format!("\"{}\"", s),
+            Self::String(s) => format!("\"{}\"", escape(&s)),
             Self::Integer(i) => format!("{}", i),
             Self::Real(r) => format!("{}", r),
-            Self::Quote(content) => format!("[{}]", content.into_iter().map(|e| e.r#type.get_lit()).collect::<Vec<String>>().join(" ")),
+            Self::Quote(content) => format!("[{}]", content.iter().map(|e| e.r#type.clone().get_lit()).collect::<Vec<String>>().join(" ")),
             Self::Var(idx) => format!("${}", idx),
-            Self::Store(idx, content) => format!("${} => ({})", idx, content.into_iter().map(|e| e.r#type.get_lit()).collect::<Vec<String>>().join(" ")),
+            Self::Store(idx, content) => format!("${} => ({})", idx, content.iter().map(|e| e.r#type.clone().get_lit()).collect::<Vec<String>>().join(" ")),
             Self::Builtin(idx) => format!("builtin#{}", idx),
         }
     }
@@ -44,14 +48,30 @@ pub struct Parser {
     input: String,
     symbols: Vec<String>,
     builtins: Vec<String>,
-    output: Vec<Expr>,
-    line: usize,
-    column: usize,
-    start: usize,
+    tokens: Vec<Token>,
     current: usize,
+    output: Vec<Expr>,
+    first_line: usize,
+    errors: Vec<Error>,
+    /// The closing tokens of the brackets being parsed, innermost last.
+    open: Vec<TokenT>,
 }
 
-const FINISHING: [Option<char>; 7] = [Some('('), Some(']'), Some(')'), Some(' '), Some('\t'), Some('\r'), Some('\n')];
+/// The number of single character edits turning `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
 
 impl Parser {
     pub fn new(input: impl ToString, symbols: Vec<String>) -> Self {
@@ -60,193 +80,202 @@ impl Parser {
             output: vec![],
             symbols,
             builtins: vec![],
-            line: 0,
-            column: 0,
-            start: 0,
+            tokens: vec![],
             current: 0,
+            first_line: 1,
+            errors: vec![],
+            open: vec![],
         };
-        to_ret.register_builtin("+");
-        to_ret.register_builtin("-");
-        to_ret.register_builtin("*");
-        to_ret.register_builtin("/");
-        to_ret.register_builtin("%");
-        to_ret.register_builtin("dup");
-        to_ret.register_builtin("app");
-        to_ret.register_builtin("cat");
-        to_ret.register_builtin("pop");
-        to_ret.register_builtin("swap");
-        to_ret.register_builtin("print_stack");
-        to_ret.register_builtin("eq");
-        to_ret.register_builtin("not");
-        to_ret.register_builtin("gt");
-        to_ret.register_builtin("lt");
-        to_ret.register_builtin("if");
+        for name in Builtin::native_names() {
+            to_ret.register_builtin(name);
+        }
         to_ret
     }
-    fn register_builtin(&mut self, builtin: impl ToString) {
-        self.builtins.push(builtin.to_string());
+    /// Renders expressions back to source, naming builtins and definitions.
+    pub fn decompile(&self, exprs: &[Expr]) -> String {
+        exprs.iter().map(|expr| match &expr.r#type {
+            ExprT::Symbol(sym) => format!("#{}", sym),
+            ExprT::String(s) => format!("\"{}\"", escape(s)),
+            ExprT::Integer(i) => format!("{}", i),
+            ExprT::Real(r) => format!("{:?}", r),
+            ExprT::Quote(content) => format!("[{}]", self.decompile(content)),
+            ExprT::Store(idx, content) => format!("let {} ( {} )", self.symbols[*idx as usize], self.decompile(content)),
+            ExprT::Var(idx) => self.symbols[*idx as usize].clone(),
+            ExprT::Builtin(idx) => self.builtins[*idx as usize].clone(),
+        }).collect::<Vec<String>>().join(" ")
     }
-    fn spaces(&mut self) -> Result<()> {
-        while !self.is_at_end() && self.peek(0) == Some(' ') {
-            self.pop()?;
-        }
-        Ok(())
+    /// Numbers the lines of the input from `line` instead of 1.
+    pub fn starting_at(mut self, line: usize) -> Self {
+        self.first_line = line;
+        self
     }
-    fn advance(&mut self, expected: char) -> Result<()> {
-        let popped = self.pop()?;
-        if popped == expected {
-            Ok(())
-        } else {
-            error!(self.line, self.column, "Expected '{}', found '{}'", expected, popped)
-        }
+    pub fn register_builtin(&mut self, builtin: impl ToString) {
+        self.builtins.push(builtin.to_string());
     }
-    fn pop(&mut self) -> Result<char> {
-        match self.input.chars().nth(self.current) {
-            Some(c) => {
+    fn pop(&mut self) -> Result<Token> {
+        match self.tokens.get(self.current) {
+            Some(token) => {
                 self.current += 1;
-                if c == '\n' {
-                    self.line += 1;
-                    self.column = 0;
-                } else {
-                    self.column += 1;
-                }
-                Ok(c)
+                Ok(token.clone())
+            }
+            None => {
+                let line = self.first_line + self.input.matches('\n').count();
+                let column = self.input.rsplit('\n').next().map_or(0, |last| last.chars().count()) + 1;
+                error!(line, column, ErrorKind::UnexpectedEof)
             }
-            None => error!(self.line, self.column, "Unexpected EOF while parsing.")
         }
     }
-    fn peek(&self, ahead: usize) -> Option<char> {
-        self.input.chars().nth(self.current + ahead)
+    fn peek(&self) -> Option<&TokenT> {
+        self.tokens.get(self.current).map(|token| &token.r#type)
     }
-    fn is_at_end(&self) -> bool {
-        self.current >= self.input.chars().count()
+    fn text(&self, token: &Token) -> &str {
+        &self.input[token.span.start..token.span.end]
     }
+    /// Parses one expression, returning `None` if it was erroneous. Only an unexpected EOF and too deep brackets are returned as errors, the other ones being collected.
     fn parse_one(&mut self) -> Result<Option<Expr>> {
-        let c = self.pop()?;
+        let token = self.pop()?;
+        let (line, column) = (token.line, token.column);
 
-        match c {
-            ' ' | '\t' | '\n' | '\r' => Ok(None),
-            '#' => {
-                let (line, column) = (self.line, self.column);
-                while !self.is_at_end() && !FINISHING.contains(&self.peek(0)){
-                    self.pop()?;
+        let r#type = match token.r#type {
+            TokenT::Integer(z) => ExprT::Integer(z),
+            TokenT::Real(r) => ExprT::Real(r),
+            TokenT::String(ref s) => ExprT::String(s.clone()),
+            TokenT::Symbol(ref sym) => ExprT::Symbol(sym.clone()),
+            TokenT::LBracket => ExprT::Quote(self.body(TokenT::RBracket, line, column)?.into()),
+            TokenT::Word(ref word) if word == "let" => return self.declare(line, column),
+            TokenT::Word(ref word) => match self.resolve(word) {
+                Some(r#type) => r#type,
+                None => {
+                    let e = self.undefined(word, line, column);
+                    self.errors.push(e);
+                    return Ok(None);
                 }
-                let start = self.start;
-                self.start = self.current;
-                Ok(Some(Expr::new(ExprT::Symbol(self.input[start + 1..self.current].to_string()), line, column)))
             }
-            ';' => {
-                while !self.is_at_end() && self.peek(0) != Some('\n') {
-                    self.pop()?;
-                }
-                Ok(None)
+            TokenT::LParen | TokenT::RParen | TokenT::RBracket => {
+                self.errors.push(Error::new(ErrorKind::UnexpectedToken(self.text(&token).to_string()), line, column));
+                return Ok(None);
             }
-            '[' => {
-                let (line, column) = (self.line, self.column);
-                let mut content = vec![];
-                while self.peek(0) != Some(']') {
-                    self.start = self.current;
-                    match self.parse_one()?  {
-                        Some(expr) => content.push(expr),
-                        None => {},
-                    }
+        };
+        Ok(Some(Expr::new(r#type, line, column)))
+    }
+    /// Parses expressions up to the `end` token, which is consumed.
+    ///
+    /// A closing token matching an enclosing bracket ends the body too, but is left to the enclosing body. `line` and `column` locate the opening bracket. Brackets nested deeper than `MAX_NESTING` stop the parsing.
+    fn body(&mut self, end: TokenT, line: usize, column: usize) -> Result<Vec<Expr>> {
+        if self.open.len() >= MAX_NESTING {
+            return error!(line, column, ErrorKind::QuotaExceeded { quota: Quota::Nesting, limit: MAX_NESTING });
+        }
+        let mut content = vec![];
+        self.open.push(end.clone());
+        loop {
+            match self.peek() {
+                Some(token) if *token == end => break,
+                Some(token @ (TokenT::RParen | TokenT::RBracket)) if self.open.contains(token) => {
+                    let token = self.tokens[self.current].clone();
+                    let expected = if end == TokenT::RParen { "')'" } else { "']'" };
+                    self.errors.push(Error::new(ErrorKind::Expected { expected: expected.to_string(), found: self.text(&token).to_string() }, token.line, token.column));
+                    self.open.pop();
+                    return Ok(content);
                 }
-                self.advance(']')?;
-                Ok(Some(Expr::new(ExprT::Quote(content), line, column)))
-            }
-            '"' => {
-                let (line, column) = (self.line, self.column);
-                while self.peek(0) != Some('"') {
-                    self.pop()?;
+                Some(_) => content.extend(self.parse_one()?),
+                None => {
+                    let opener = if end == TokenT::RParen { '(' } else { '[' };
+                    return self.pop().map(|_| vec![]).map_err(|e| if e.notes.is_empty() {
+                        e.with_note(format!("help: the '{}' at {}:{} is never closed.", opener, line, column))
+                    } else {
+                        e
+                    });
                 }
-                self.advance('"')?;
-                Ok(Some(Expr::new(ExprT::String(self.input[self.start + 1..self.current - 1].to_string()), line, column)))
-            }
-            x => if x.is_digit(10) {
-                self.number()
-            } else {
-                self.identifier()
             }
         }
+        self.open.pop();
+        self.pop()?;
+        Ok(content)
     }
-    fn identifier(&mut self) -> Result<Option<Expr>> {
-        let (line, column) = (self.line, self.column);
-        while !self.is_at_end() && !FINISHING.contains(&self.peek(0)) {
-            self.pop()?;
-        }
-        let raw = self.input[self.start..self.current].to_string();
-        if raw == "let" {
-            Ok(Some(self.declare()?))
-        } else if self.builtins.contains(&raw) {
-            Ok(Some(Expr::new(ExprT::Builtin(self.builtins.iter().position(|builtin| builtin.to_string() == raw).unwrap() as u16), line, column)))
+    /// Looks up a word among the builtins, then among the user definitions.
+    pub fn resolve(&self, name: &str) -> Option<ExprT> {
+        if let Some(idx) = self.builtins.iter().position(|builtin| builtin == name) {
+            Some(ExprT::Builtin(idx as u16))
         } else {
-            if self.symbols.contains(&raw) {
-                Ok(Some(Expr::new(ExprT::Var(self.symbols.iter().position(|sym| sym.to_string() == raw).unwrap() as u16), line, column)))
-            } else {
-                error!(line, column, "Use of an undefined variable: {}.", raw)
-            }
+            self.symbols.iter().position(|sym| sym == name).map(|idx| ExprT::Var(idx as u16))
         }
     }
-    fn declare(&mut self) -> Result<Expr> {
-        let (line, column) = (self.line, self.column);
-        self.spaces()?;
-        self.start = self.current;
-        while !FINISHING.contains(&self.peek(0)) {
-            self.pop()?;
+    fn undefined(&self, word: &str, line: usize, column: usize) -> Error {
+        let error = Error::new(ErrorKind::UndefinedWord(word.to_string()), line, column);
+        let closest = self.builtins.iter().chain(&self.symbols)
+            .map(|name| (distance(word, name), name))
+            .filter(|(distance, name)| *distance <= 2 && *distance < name.chars().count())
+            .min_by_key(|(distance, _)| *distance);
+        match closest {
+            Some((_, name)) => error.with_note(format!("help: a word with a similar name exists: `{}`.", name)),
+            None => error,
         }
-        let name = self.input[self.start..self.current].to_string();
-        self.spaces()?;
-        self.advance('(')?;
-        let mut content = vec![];
-        while self.peek(0) != Some(')') {
-            self.start = self.current;
-            match self.parse_one()? {
-                Some(expr) => content.push(expr),
-                None => {}
+    }
+    fn declare(&mut self, line: usize, column: usize) -> Result<Option<Expr>> {
+        let token = self.pop()?;
+        let name = match token.r#type {
+            TokenT::Word(name) => Some(name),
+            _ => {
+                self.errors.push(Error::new(ErrorKind::Expected { expected: "a name".to_string(), found: self.text(&token).to_string() }, token.line, token.column));
+                None
             }
+        };
+        let token = self.pop()?;
+        if token.r#type != TokenT::LParen {
+            self.errors.push(Error::new(ErrorKind::Expected { expected: "'('".to_string(), found: self.text(&token).to_string() }, token.line, token.column));
+            // Let the token be parsed again, as it might start the next expression.
+            self.current -= 1;
+            return Ok(None);
         }
-        self.advance(')')?;
-        let idx = if self.symbols.contains(&name) {
-            self.symbols.iter().position(|sym| sym.to_string() == name).unwrap()
-        } else {
-            self.symbols.push(name);
-            self.symbols.len() - 1
+        let content = self.body(TokenT::RParen, token.line, token.column)?;
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(None),
         };
-        Ok(Expr::new(ExprT::Store(idx as u16, content), line, column))
-    }
-    fn number(&mut self) -> Result<Option<Expr>> {
-        let (line, column) = (self.line, self.column);
-        while let Some(c) = self.peek(0) {
-            if c.is_digit(10) {
-                self.pop()?;
-            } else {
-                break;
+        let idx = match self.symbols.iter().position(|sym| *sym == name) {
+            Some(idx) => idx,
+            None => {
+                self.symbols.push(name);
+                self.symbols.len() - 1
             }
-        }
-        if self.peek(0) == Some('.') {
-            self.pop()?;
-        }
-        while let Some(c) = self.peek(0) {
-            if c.is_digit(10) {
-                self.pop()?;
-            } else {
-                break;
+        };
+        Ok(Some(Expr::new(ExprT::Store(idx as u16, content.into()), line, column)))
+    }
+    /// Registers the names of every `let` of the input, so that definitions can refer to themselves and to the ones that follow.
+    fn predeclare(&mut self) {
+        for window in self.tokens.windows(3) {
+            if let [Token { r#type: TokenT::Word(keyword), .. }, Token { r#type: TokenT::Word(name), .. }, Token { r#type: TokenT::LParen, .. }] = window {
+                if keyword == "let" && !self.symbols.contains(name) {
+                    self.symbols.push(name.clone());
+                }
             }
         }
-        let raw = self.input[self.start..self.current].to_string();
-        Ok(Some(Expr::new(match raw.parse::<i32>() {
-            Ok(z) => ExprT::Integer(z),
-            Err(_) => ExprT::Real(raw.parse::<f32>().unwrap_or(3.1415926535897932))
-        }, line, column)))    
-    }
-    pub fn parse(&mut self) -> Result<(Vec<Expr>, Vec<String>)> {
-        while !self.is_at_end() {
-            match self.parse_one()? {
-                Some(expr) => self.output.push(expr),
-                None => {},
+    }
+    /// Parses the whole input, returning every error found, in order.
+    pub fn parse(&mut self) -> std::result::Result<(Vec<Expr>, Vec<String>), Vec<Error>> {
+        let (tokens, errors) = Lexer::new(&self.input).starting_at(self.first_line).tokenize();
+        self.tokens = tokens;
+        self.errors = errors;
+        self.current = 0;
+        self.predeclare();
+        while self.current < self.tokens.len() {
+            match self.parse_one() {
+                Ok(expr) => self.output.extend(expr),
+                Err(e) => {
+                    // An unterminated string already made the lexer report the EOF.
+                    if !self.errors.iter().any(|e| e.kind == ErrorKind::UnexpectedEof) {
+                        self.errors.push(e);
+                    }
+                    break;
+                }
             }
-            self.start = self.current;
         }
-        Ok((self.output.clone(), self.symbols.clone()))
+        if self.errors.is_empty() {
+            Ok((self.output.clone(), self.symbols.clone()))
+        } else {
+            let mut errors = std::mem::take(&mut self.errors);
+            errors.sort_by_key(|e| (e.line, e.column));
+            Err(errors)
+        }
     }
 }
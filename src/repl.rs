@@ -0,0 +1,474 @@
+use crate::{print_err, print_failure, strip_shebang};
+use kotek::{Interpreter, Value, Error, lexer, parser::{Parser, ExprT}};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::{Validator, ValidationContext, ValidationResult},
+    Config, Context, Editor,
+};
+use std::{borrow::Cow, env, fs, io, path::{Path, PathBuf}};
+
+const COMMANDS: [&str; 12] = [":stack", ":clear", ":defs", ":type", ":load", ":reset", ":save", ":restore", ":help", ":quit", "help", "quit"];
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()[]".contains(c)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Token {
+    Space,
+    Open(char),
+    Close(char),
+    Number,
+    String,
+    Symbol,
+    Comment,
+    Word,
+}
+
+/// Splits a line the way `Lexer` does, without failing on unfinished input, and returns the byte range of each token.
+fn tokenize(line: &str) -> Vec<(usize, usize, Token)> {
+    let mut tokens = vec![];
+    let mut chars = line.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            '(' | '[' => Token::Open(c),
+            ')' | ']' => Token::Close(c),
+            c if c.is_whitespace() => Token::Space,
+            '"' => {
+                let end = lexer::string_end(line, start).unwrap_or(line.len());
+                while chars.next_if(|(idx, _)| *idx < end).is_some() {}
+                Token::String
+            }
+            ';' => {
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+                Token::Comment
+            }
+            _ => {
+                while chars.next_if(|(_, c)| !lexer::is_finishing(*c)).is_some() {}
+                if c == '#' {
+                    Token::Symbol
+                } else if c.is_ascii_digit() || (c == '-' && line[start + 1..].starts_with(|c: char| c.is_ascii_digit())) {
+                    Token::Number
+                } else {
+                    Token::Word
+                }
+            }
+        };
+        let end = chars.peek().map_or(line.len(), |(idx, _)| *idx);
+        tokens.push((start, end, token));
+    }
+    tokens
+}
+
+/// Pairs the brackets of `tokens`, returning the pairs and the unmatched closing brackets.
+fn brackets(tokens: &[(usize, usize, Token)]) -> (Vec<(usize, usize)>, Vec<usize>) {
+    let mut open = vec![];
+    let mut pairs = vec![];
+    let mut unmatched = vec![];
+    for (start, _, token) in tokens {
+        match token {
+            Token::Open(c) => open.push((*start, *c)),
+            Token::Close(c) => match open.last() {
+                Some((idx, o)) if (*o, *c) == ('(', ')') || (*o, *c) == ('[', ']') => {
+                    pairs.push((*idx, *start));
+                    open.pop();
+                }
+                _ => unmatched.push(*start),
+            }
+            _ => {}
+        }
+    }
+    (pairs, unmatched)
+}
+
+const RESET: &str = "\x1b[0m";
+const LET: &str = "\x1b[1;35m";
+const BUILTIN: &str = "\x1b[0;34m";
+const DEFINITION: &str = "\x1b[0;36m";
+const UNDEFINED: &str = "\x1b[0;31m";
+const NUMBER: &str = "\x1b[0;33m";
+const STRING: &str = "\x1b[0;32m";
+const SYMBOL: &str = "\x1b[0;35m";
+const COMMENT: &str = "\x1b[0;90m";
+const MATCHING: &str = "\x1b[1;34m";
+
+/// Completes, highlights and validates REPL input.
+struct Helper {
+    words: Vec<String>,
+    parser: Parser,
+    /// The previous lines of an incomplete input.
+    pending: String,
+}
+impl Helper {
+    fn new(interpreter: &Interpreter) -> Self {
+        let mut helper = Self {
+            words: vec![],
+            parser: interpreter.parser(""),
+            pending: String::new(),
+        };
+        helper.refresh(interpreter);
+        helper
+    }
+    fn refresh(&mut self, interpreter: &Interpreter) {
+        self.parser = interpreter.parser("");
+        self.words = interpreter.builtins().iter().map(|builtin| builtin.name.clone())
+            .chain(interpreter.symbols().iter().cloned())
+            .chain(std::iter::once("let".to_string()))
+            .collect();
+        self.words.sort();
+        self.words.dedup();
+    }
+}
+impl Completer for Helper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(is_delimiter).map_or(0, |idx| idx + line[idx..].chars().next().unwrap().len_utf8());
+        let prefix = &line[start..pos];
+        let is_command = line[..start].trim().is_empty();
+        let candidates = self.words.iter().map(String::as_str)
+            .chain(COMMANDS.iter().copied().filter(|_| is_command))
+            .filter(|word| word.starts_with(prefix))
+            .map(|word| Pair {
+                display: word.to_string(),
+                replacement: word.to_string(),
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+impl Hinter for Helper {
+    type Hint = String;
+}
+impl Helper {
+    /// Splits a REPL command from its argument, if `line` is one.
+    fn command<'l>(&self, line: &'l str) -> Option<(&'l str, &'l str)> {
+        if !self.pending.is_empty() {
+            return None;
+        }
+        let start = line.len() - line.trim_start().len();
+        let end = line[start..].find(char::is_whitespace).map_or(line.len(), |idx| start + idx);
+        if COMMANDS.contains(&&line[start..end]) {
+            Some((&line[..end], &line[end..]))
+        } else {
+            None
+        }
+    }
+}
+impl Highlighter for Helper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        match self.command(line) {
+            Some((name, arg)) if name.trim() == ":type" => Cow::Owned(format!("{}{}{}{}", LET, name, RESET, self.highlight(arg, pos.saturating_sub(name.len())))),
+            Some((name, arg)) => Cow::Owned(format!("{}{}{}{}", LET, name, RESET, arg)),
+            None => self.highlight_code(line, pos),
+        }
+    }
+    fn highlight_char(&self, _: &str, _: usize) -> bool {
+        true
+    }
+}
+impl Helper {
+    fn highlight_code<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        let tokens = tokenize(line);
+        let (pairs, unmatched) = brackets(&tokens);
+        let cursor = [pos, pos.saturating_sub(1)];
+        let matching = pairs.iter()
+            .find(|(open, close)| cursor.contains(open) || cursor.contains(close))
+            .map_or(vec![], |(open, close)| vec![*open, *close]);
+
+        // Words defined in this input are not known by the parser yet.
+        let mut defined = vec![];
+        for text in [self.pending.as_str(), line].iter() {
+            let words = tokenize(text).into_iter().filter(|(_, _, token)| *token == Token::Word).map(|(start, end, _)| &text[start..end]).collect::<Vec<_>>();
+            defined.extend(words.windows(2).filter(|pair| pair[0] == "let").map(|pair| pair[1]));
+        }
+
+        let mut highlighted = String::with_capacity(line.len() * 2);
+        let mut previous = "";
+        for (start, end, token) in tokens {
+            let text = &line[start..end];
+            let colour = match token {
+                Token::Space => "",
+                Token::Open(_) | Token::Close(_) => if matching.contains(&start) {
+                    MATCHING
+                } else if unmatched.contains(&start) {
+                    UNDEFINED
+                } else {
+                    ""
+                }
+                Token::Number => NUMBER,
+                Token::String => STRING,
+                Token::Symbol => SYMBOL,
+                Token::Comment => COMMENT,
+                Token::Word => if text == "let" {
+                    LET
+                } else if previous == "let" || defined.contains(&text) {
+                    DEFINITION
+                } else {
+                    match self.parser.resolve(text) {
+                        Some(ExprT::Builtin(_)) => BUILTIN,
+                        Some(_) => DEFINITION,
+                        None => UNDEFINED,
+                    }
+                }
+            };
+            if token != Token::Space {
+                previous = text;
+            }
+            if colour.is_empty() {
+                highlighted.push_str(text);
+            } else {
+                highlighted.push_str(colour);
+                highlighted.push_str(text);
+                highlighted.push_str(RESET);
+            }
+        }
+        Cow::Owned(highlighted)
+    }
+}
+impl Validator for Helper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if self.command(ctx.input()).is_some() {
+            return Ok(ValidationResult::Valid(None));
+        }
+        let input = if self.pending.is_empty() {
+            ctx.input().to_string()
+        } else {
+            format!("{}\n{}", self.pending, ctx.input())
+        };
+        let tokens = tokenize(&input);
+        Ok(match brackets(&tokens).1.first() {
+            Some(idx) => ValidationResult::Invalid(Some(format!("  <- unmatched '{}'", &input[*idx..*idx + 1]))),
+            None => ValidationResult::Valid(None),
+        })
+    }
+}
+impl rustyline::Helper for Helper {}
+
+const COMMANDS_HELP: &str = ":stack          Print the stack with the type of each value.
+:clear          Empty the stack.
+:defs           List the definitions, with their inferred stack effects.
+:type <expr>    Print the type of the value <expr> leaves on an empty stack, without changing the session.
+:load <file>    Evaluate a file in the session.
+:reset          Forget the definitions and empty the stack.
+:save <file>    Save the definitions and the stack to a session file.
+:restore <file> Replace the definitions and the stack with the ones of a session file.
+:help [<word>]  Print this message, or the documentation of a builtin.
+:quit           Leave the REPL.";
+
+fn help(interpreter: &Interpreter, word: &str) {
+    if word.is_empty() {
+        let names = interpreter.builtins().iter().map(|builtin| builtin.name.as_str()).collect::<Vec<_>>();
+        println!("Builtins: {}", names.join(" "));
+        println!("Type `help <word>` to get the documentation of a builtin.");
+        println!();
+        println!("{}", COMMANDS_HELP);
+    } else if let Some(builtin) = interpreter.builtins().iter().find(|builtin| builtin.name == word) {
+        println!("{} {} :: {}", builtin.name, builtin.signature(), builtin.types);
+        println!("  {}", builtin.doc);
+    } else if interpreter.symbols().iter().any(|sym| sym == word) {
+        println!("{} is a user definition.", word);
+    } else {
+        print_failure(format!("Unknown word: {}.", word));
+    }
+}
+
+/// Parses and evaluates `src`, returning every parse error, or the evaluation error.
+fn eval(interpreter: &mut Interpreter, name: impl ToString, src: impl ToString) -> std::result::Result<Option<Value>, Vec<Error>> {
+    let expressions = interpreter.parse_named(name, src)?;
+    let errors = interpreter.check(&expressions);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    interpreter.eval(expressions).map_err(|e| vec![e])
+}
+
+fn print_top(interpreter: &Interpreter, res: std::result::Result<Option<Value>, Vec<Error>>) {
+    match res {
+        Ok(val) => if let Some(top) = val {
+            println!("=> {} :: {}", interpreter.show(&top), top.get_type());
+        }
+        Err(errors) => errors.into_iter().for_each(|e| print_err(interpreter, e)),
+    }
+}
+
+fn save_session(interpreter: &Interpreter, path: &Path) -> std::result::Result<(), String> {
+    fs::write(path, interpreter.save_session()).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+fn restore_session(interpreter: &mut Interpreter, path: &Path) {
+    match fs::read_to_string(path) {
+        Ok(session) => if let Err(e) = interpreter.restore_session(&session) {
+            print_failure(format!("Failed to restore {}:", path.display()));
+            print_err(interpreter, e);
+        }
+        Err(e) => print_failure(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+/// Runs a REPL command, returning `None` if `line` is not one, and `Some(false)` to leave the REPL.
+fn command(interpreter: &mut Interpreter, line: &str) -> Option<bool> {
+    let line = line.trim();
+    let (name, arg) = match line.find(char::is_whitespace) {
+        Some(idx) => (&line[..idx], line[idx..].trim()),
+        None => (line, ""),
+    };
+    match name {
+        "quit" | ":quit" if arg.is_empty() => return Some(false),
+        "help" | ":help" => help(interpreter, arg),
+        ":stack" => for (idx, val) in interpreter.stack().iter().enumerate() {
+            println!("{}: {} :: {}", idx, interpreter.show(val), val.clone().get_type());
+        }
+        ":clear" => interpreter.clear(),
+        ":defs" => for name in interpreter.symbols() {
+            if let Some(body) = interpreter.definition(name) {
+                match interpreter.effect(name) {
+                    Some(effect) => println!("let {} ( {} ) ; {}", name, interpreter.decompile(body), effect),
+                    None => println!("let {} ( {} )", name, interpreter.decompile(body)),
+                }
+            }
+        }
+        ":type" => {
+            let checkpoint = interpreter.checkpoint();
+            // The values already on the stack would be taken for the result.
+            interpreter.clear();
+            match eval(interpreter, "<repl>", arg) {
+                Ok(Some(top)) => println!("{} :: {}", arg, top.get_type()),
+                Ok(None) => println!("{} leaves the stack empty.", arg),
+                Err(errors) => errors.into_iter().for_each(|e| print_err(interpreter, e)),
+            }
+            interpreter.rollback(checkpoint);
+        }
+        ":load" => match fs::read_to_string(arg) {
+            Ok(source) => {
+                let res = eval(interpreter, arg, strip_shebang(source));
+                print_top(interpreter, res);
+            }
+            Err(e) => print_failure(format!("Failed to read {}: {}", arg, e)),
+        }
+        ":reset" => interpreter.reset(),
+        ":save" => if let Err(e) = save_session(interpreter, Path::new(arg)) {
+            print_failure(e);
+        }
+        ":restore" => restore_session(interpreter, Path::new(arg)),
+        _ if name.starts_with(':') => print_failure(format!("Unknown command: {}. Type :help to list the commands.", name)),
+        _ => return None,
+    }
+    Some(true)
+}
+
+const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+/// `$XDG_DATA_HOME/kotek/history`, defaulting to `~/.local/share/kotek/history` (`%APPDATA%\kotek\history` on Windows).
+fn history_path() -> Option<PathBuf> {
+    let data = env::var_os("XDG_DATA_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share")))?;
+    Some(data.join("kotek").join("history"))
+}
+
+/// `$KOTEK_INIT`, defaulting to `~/.kotekrc`, and whether it was explicitly requested.
+fn init_path() -> Option<(PathBuf, bool)> {
+    match env::var_os("KOTEK_INIT") {
+        Some(path) => Some((PathBuf::from(path), true)),
+        None => env::var_os("HOME").map(|home| (Path::new(&home).join(".kotekrc"), false)),
+    }
+}
+
+fn load_init(interpreter: &mut Interpreter) {
+    let (path, explicit) = match init_path() {
+        Some(init) => init,
+        None => return,
+    };
+    match fs::read_to_string(&path) {
+        Ok(source) => if let Err(errors) = eval(interpreter, path.display(), strip_shebang(source)) {
+            errors.into_iter().for_each(|e| print_err(interpreter, e));
+        }
+        Err(e) => if explicit || e.kind() != io::ErrorKind::NotFound {
+            print_failure(format!("Failed to read {}: {}", path.display(), e));
+        }
+    }
+}
+
+/// Starts the REPL, restoring `session` if it exists and saving it on exit.
+pub fn repl(mut interpreter: Interpreter, session: Option<PathBuf>) {
+    if let Some(session) = session.as_deref().filter(|session| session.exists()) {
+        restore_session(&mut interpreter, session);
+    }
+    // After the session, which replaces every definition, so that the ones of the init file are up to date.
+    load_init(&mut interpreter);
+
+    let history_size = env::var("KOTEK_HISTSIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_HISTORY_SIZE);
+    let mut reader = Editor::<Helper>::with_config(Config::builder().max_history_size(history_size).build());
+    reader.set_helper(Some(Helper::new(&interpreter)));
+    let history = history_path();
+    if let Some(history) = &history {
+        // A missing history file just means that this is the first session.
+        let _ = reader.load_history(history);
+    }
+
+    let mut buffer = String::new();
+    loop {
+        if let Some(helper) = reader.helper_mut() {
+            helper.pending = buffer.clone();
+        }
+        let line = reader.readline(if buffer.is_empty() { "kotek> " } else { "  ...> " });
+        match line {
+            Ok(line) => {
+                let line = line.trim_end_matches(['\n', '\r']);
+                if buffer.is_empty() {
+                    if let Some(proceed) = command(&mut interpreter, line) {
+                        if !proceed {
+                            break;
+                        }
+                        reader.add_history_entry(line);
+                        if let Some(helper) = reader.helper_mut() {
+                            helper.refresh(&interpreter);
+                        }
+                        continue;
+                    }
+                } else {
+                    buffer.push('\n');
+                }
+                buffer.push_str(line);
+                if !lexer::is_complete(&buffer) {
+                    continue;
+                }
+
+                let input = std::mem::take(&mut buffer);
+                reader.add_history_entry(input.as_str());
+                let res = eval(&mut interpreter, "<repl>", input);
+                print_top(&interpreter, res);
+                if let Some(helper) = reader.helper_mut() {
+                    helper.refresh(&interpreter);
+                }
+            }
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                println!("=> #Interrupt");
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(_) => {
+                eprintln!("An error occured while reading input, please retry.");
+            }
+        }
+    }
+
+    if let Some(session) = session {
+        if let Err(e) = save_session(&interpreter, &session) {
+            print_failure(e);
+        }
+    }
+    if let Some(history) = history {
+        let saved = match history.parent() {
+            Some(dir) => fs::create_dir_all(dir).map_err(ReadlineError::from),
+            None => Ok(()),
+        }.and_then(|_| reader.save_history(&history));
+        if let Err(e) = saved {
+            print_failure(format!("Failed to save the history to {}: {}", history.display(), e));
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{builtins::{quote, underflow}, bytecode::Op, eval::{too_deep, Evaluator, unevaluated, Value}, Result, Error};
+
+/// A block being run, and the index of its next instruction.
+type Frame = (u32, usize);
+
+impl Evaluator {
+    /// Runs `block` of the program, and the blocks it calls, to completion.
+    ///
+    /// Each instruction takes one step of fuel. Errors without a position are given the one of the current instruction.
+    pub(crate) fn run_block(&mut self, block: u32) -> Result<()> {
+        let mut frames: Vec<Frame> = vec![(block, 0)];
+        while let Some(&(block, pc)) = frames.last() {
+            let code = &self.program.blocks[block as usize];
+            let (op, (line, column)) = (code.code[pc], code.positions[pc]);
+            if let Some(frame) = frames.last_mut() {
+                frame.1 += 1;
+            }
+            self.exec(op, &mut frames, line, column).map_err(|e| if e.line == 0 {
+                Error { line, column, ..e }
+            } else {
+                e
+            })?;
+        }
+        Ok(())
+    }
+    fn exec(&mut self, op: Op, frames: &mut Vec<Frame>, line: usize, column: usize) -> Result<()> {
+        self.tick(line, column)?;
+        match op {
+            Op::Push(c) => {
+                let val = self.program.constants[c as usize].clone();
+                self.push(val)
+            }
+            Op::Builtin(b) => {
+                // A host function may schedule bodies for the tree-walker with `call`.
+                let base = self.frames.len();
+                let res = self.call_builtin(b as usize, line, column).and_then(|_| self.run_until(base));
+                self.frames.truncate(base);
+                res
+            }
+            Op::Call(var) => match self.vars.get(var as usize).and_then(Option::as_ref) {
+                Some(body) => {
+                    let (_, block) = self.program.block_of(body);
+                    self.enter(frames, block, line, column)
+                }
+                None => Err(unevaluated(var, line, column)),
+            }
+            Op::Store(var, c) => {
+                if let Value::Quote(body) = &self.program.constants[c as usize] {
+                    let var = var as usize;
+                    if var >= self.vars.len() {
+                        self.vars.resize(var + 1, None);
+                    }
+                    self.vars[var] = Some(body.clone());
+                }
+                Ok(())
+            }
+            Op::App if self.stack.is_empty() => Err(underflow("app", 1, 0, line, column)),
+            Op::App => {
+                let val = self.pop(line, column)?;
+                let (_, block) = self.program.block_of(&quote(val, line, column)?);
+                self.enter(frames, block, line, column)
+            }
+            Op::If if self.stack.len() < 3 => Err(underflow("if", 3, self.stack.len(), line, column)),
+            Op::If => {
+                let branch = self.branch(line, column)?;
+                let (_, block) = self.program.block_of(&quote(branch, line, column)?);
+                self.enter(frames, block, line, column)
+            }
+            Op::JumpUnless(target) => {
+                if self.stack.is_empty() {
+                    // The branches of an inlined `if` are not on the stack, but the tree-walker would have pushed them.
+                    return Err(underflow("if", 3, 2, line, column));
+                }
+                if !self.condition(line, column)? {
+                    self.jump(frames, target);
+                }
+                Ok(())
+            }
+            Op::Jump(target) => {
+                self.jump(frames, target);
+                Ok(())
+            }
+            Op::Return => {
+                frames.pop();
+                Ok(())
+            }
+        }
+    }
+    fn jump(&self, frames: &mut [Frame], target: u32) {
+        if let Some(frame) = frames.last_mut() {
+            frame.1 = target as usize;
+        }
+    }
+    /// Calls `block`, reusing the current frame when the next instruction returns, so that a tail call does not count towards `max_depth`.
+    fn enter(&self, frames: &mut Vec<Frame>, block: u32, line: usize, column: usize) -> Result<()> {
+        if let Some(&(current, pc)) = frames.last() {
+            if self.program.blocks[current as usize].code[pc] == Op::Return {
+                frames.pop();
+            }
+        }
+        if frames.len() + self.frames.len() >= self.max_depth {
+            return Err(too_deep(self.max_depth, line, column));
+        }
+        frames.push((block, 0));
+        Ok(())
+    }
+}
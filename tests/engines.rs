@@ -0,0 +1,66 @@
+//! Runs the same programs on the tree-walker and on the bytecode VM, and compares what they leave.
+
+use kotek::{Interpreter, ErrorKind, eval::Engine};
+
+const CORPUS: &[&str] = &[
+    "3 4 + dup *",
+    "1.5 2.5 + 2.0 *",
+    "\"x\" \"y\" cat",
+    "#t not not \"a\" \"b\" cat",
+    "1 2 3 swap swap dup pop",
+    "let fact ( dup 1 eq [pop 1] [dup 1 - fact *] if ) 10 fact",
+    "let fib ( dup 2 lt [] [dup 1 - fib swap 2 - fib +] if ) 15 fib",
+    "let even ( dup 0 eq [pop #t] [1 - odd] if ) let odd ( dup 0 eq [pop #f] [1 - even] if ) 7 even 10 odd",
+    "let loop ( dup 0 eq [pop] [1 - loop] if ) 100000 loop",
+    "let deep ( dup 0 eq [pop] [1 - deep 1 +] if ) 200000 deep",
+    "let square ( dup * ) let quad ( square square ) 3 quad 5 swap quad",
+    "let add3 ( 3 + ) 4 add3 add3",
+    "let q ( [#t] ) q app [1] [2] if",
+    "let f ( let g ( 1 ) ) f g",
+    "let f ( g ) let g ( 1 ) f",
+    "let f ( g ) f let g ( 1 )",
+    "[1 2 +] app [3] app",
+    "[1 [2 [3]]] dup app app app",
+    "[dup *] 4 swap app",
+    "[square] let square ( dup * ) [square] app",
+    "#t [1] [2] if #f [1] [2] if",
+    "#t [#f [1] [2] if] [3] if",
+    "1 2 lt [1] [2] if",
+    "[1] [2] if",
+    "1 [1] [2] if",
+    "#x [1] [2] if",
+    "#t [1] 2 if",
+    "5 [1] swap if",
+    "if",
+    "app",
+    "1 app",
+    "[app] app",
+    "swap",
+    "1 0 /",
+    "2147483647 1 +",
+    "\"a\" 1 +",
+    "1.5 2 +",
+    "let sq ( dup * ) \"x\" sq",
+];
+
+/// What evaluating `src` leaves: the stack, or the kind and position of the error.
+fn run(src: &str, engine: Engine, optimize: bool) -> Result<Vec<String>, (ErrorKind, usize, usize)> {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_engine(engine);
+    interpreter.set_optimize(optimize);
+    // The errors raised during evaluation are the ones compared.
+    interpreter.set_check(false);
+    match interpreter.eval_str(src) {
+        Ok(_) => Ok(interpreter.stack().iter().map(|val| interpreter.show(val)).collect()),
+        Err(e) => Err((e.kind, e.line, e.column)),
+    }
+}
+
+#[test]
+fn engines_agree() {
+    for optimize in [false, true] {
+        for src in CORPUS {
+            assert_eq!(run(src, Engine::TreeWalker, optimize), run(src, Engine::Bytecode, optimize), "{} (optimized: {})", src, optimize);
+        }
+    }
+}